@@ -1,3 +1,19 @@
 pub mod agent;
+pub mod config;
+pub mod control;
+pub mod daemon;
+pub mod hooks;
+pub mod lock;
+pub mod logging;
+pub mod metrics;
+pub mod mqtt;
+pub mod notify;
+pub mod plan;
+pub mod presence;
+pub mod redact;
+pub mod secret;
+pub mod server;
+pub mod telegram;
+pub mod transport;
 pub mod utils;
 pub mod workday_schedule;
@@ -1,13 +1,24 @@
+use super::config::MayoConfig;
+use super::metrics;
+use super::redact::Redactor;
+use super::transport::{Cassette, LiveTransport, RecordingTransport, ReplayTransport, Transport};
 use super::workday_schedule::WorkdaySchedule;
-use crate::apollo::utils::to_resp_json;
-use chrono::{Datelike, Local};
+use crate::apollo::utils::{to_resp_json, HttpResponse};
+use chrono::{Datelike, Local, NaiveDate};
 use reqwest;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::fmt::Display;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, info_span};
 use visdom::Vis;
 
+/// Longest body written into the http trace.
+const MAX_TRACE_BODY: usize = 16 * 1024;
+
 const USER_AGENT: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/115.0.0.0 Safari/537.36";
 
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub enum PunchType {
     PunchIn = 1,
     PunchOut = 2,
@@ -22,32 +33,125 @@ impl Display for PunchType {
     }
 }
 
+fn truncate(body: &str) -> String {
+    if body.len() <= MAX_TRACE_BODY {
+        return body.to_string();
+    }
+
+    let mut end = MAX_TRACE_BODY;
+    while !body.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}... ({} bytes truncated)", &body[..end], body.len() - end)
+}
+
+/// First line, then headers one per line, then the (truncated) body after a blank line.
+fn trace_message(first_line: String, headers: &[String], body: &str) -> String {
+    let mut message = first_line;
+    for header in headers {
+        message.push('\n');
+        message.push_str(header);
+    }
+    if !body.is_empty() {
+        message.push_str("\n\n");
+        message.push_str(&truncate(body));
+    }
+    message
+}
+
 pub struct ApolloAgent {
     username: String,
     password: String,
     company: String,
 
     client: reqwest::blocking::Client,
+    transport: Box<dyn Transport>,
 
     auth_data: Option<Value>,
+    mayo: MayoConfig,
+
+    dry_run: bool,
+    trace_http: bool,
 }
 
 impl ApolloAgent {
     pub fn new<S: Into<String>>(username: S, password: S, company: S) -> Self {
+        let client = reqwest::blocking::Client::builder()
+            .user_agent(USER_AGENT)
+            .cookie_store(true)
+            .build()
+            .unwrap();
+
         ApolloAgent {
             username: username.into(),
             password: password.into(),
             company: company.into(),
-            client: reqwest::blocking::Client::builder()
-                .user_agent(USER_AGENT)
-                .cookie_store(true)
-                .build()
-                .unwrap(),
+            transport: Box::new(LiveTransport::new(client.clone())),
+            client,
             auth_data: None,
+            mayo: MayoConfig::default(),
+            dry_run: false,
+            trace_http: false,
         }
     }
 
+    /// Switches the account, the next `login` uses the new credentials.
+    pub fn set_credentials<S: Into<String>>(&mut self, username: S, password: S, company: S) {
+        self.username = username.into();
+        self.password = password.into();
+        self.company = company.into();
+        self.auth_data = None;
+    }
+
+    /// Switches the Mayo servers and login locale, the next `login` uses them.
+    pub fn set_mayo(&mut self, mayo: &MayoConfig) {
+        self.mayo = mayo.clone();
+        self.auth_data = None;
+    }
+
+    /// When enabled, `punch_card` only reports the payload it would have sent.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// When enabled, every request and response is logged with credentials redacted.
+    pub fn set_trace_http(&mut self, trace_http: bool) {
+        self.trace_http = trace_http;
+    }
+
+    /// Records every request and response of this agent, redacted, into the cassette at `path`.
+    pub fn record_http(&mut self, path: &str) {
+        let live = std::mem::replace(
+            &mut self.transport,
+            Box::new(LiveTransport::new(self.client.clone())),
+        );
+        self.transport = Box::new(RecordingTransport::new(live, path, self.redactor()));
+    }
+
+    /// Serves responses from the cassette at `path` instead of calling Mayo.
+    pub fn replay_http(&mut self, path: &str) -> Result<(), String> {
+        self.transport = Box::new(ReplayTransport::new(Cassette::load(path)?));
+        Ok(())
+    }
+
     pub fn login(&mut self) -> Result<(), String> {
+        let _span =
+            info_span!("login", company = %self.company, username = %self.username).entered();
+
+        let result = self.do_login();
+        match &result {
+            Ok(_) => debug!("login succeeded"),
+            Err(e) => error!("login failed: {}", e),
+        }
+        metrics::record_login(result.is_ok());
+        result
+    }
+
+    fn do_login(&mut self) -> Result<(), String> {
         let auth_data = self.get_login_req_token()?;
 
         self.check_ticket(auth_data["code"].as_str().unwrap())?;
@@ -59,8 +163,100 @@ impl ApolloAgent {
         Ok(())
     }
 
+    fn send(&self, builder: reqwest::blocking::RequestBuilder) -> Result<HttpResponse, String> {
+        let request = builder.build().map_err(|err| err.to_string())?;
+        let endpoint = request.url().path().to_string();
+        if self.trace_http {
+            self.trace_request(&request);
+        }
+
+        let started = Instant::now();
+        let resp = self.transport.execute(request);
+        let elapsed = started.elapsed();
+        metrics::observe_http_request(&endpoint, elapsed);
+        debug!("{} took {:?}", endpoint, elapsed);
+
+        if self.trace_http {
+            self.trace_response(&endpoint, &resp, elapsed);
+        }
+
+        resp
+    }
+
+    fn trace_request(&self, request: &reqwest::blocking::Request) {
+        let redactor = self.redactor();
+        let headers: Vec<String> = request
+            .headers()
+            .iter()
+            .map(|(k, v)| {
+                format!(
+                    "{}: {}",
+                    k,
+                    redactor.header(k.as_str(), &String::from_utf8_lossy(v.as_bytes()))
+                )
+            })
+            .collect();
+        let body = request
+            .body()
+            .and_then(|b| b.as_bytes())
+            .map(|b| redactor.body(&String::from_utf8_lossy(b)))
+            .unwrap_or_default();
+
+        info!(
+            target: "http_trace",
+            "{}",
+            trace_message(
+                format!(
+                    "--> {} {}",
+                    request.method(),
+                    redactor.url(request.url().as_str())
+                ),
+                &headers,
+                &body
+            )
+        );
+    }
+
+    fn trace_response(
+        &self,
+        endpoint: &str,
+        resp: &Result<HttpResponse, String>,
+        elapsed: Duration,
+    ) {
+        let redactor = self.redactor();
+        match resp {
+            Ok(resp) => {
+                let headers: Vec<String> = resp
+                    .headers
+                    .iter()
+                    .map(|(k, v)| format!("{}: {}", k, redactor.header(k, v)))
+                    .collect();
+                info!(
+                    target: "http_trace",
+                    "{}",
+                    trace_message(
+                        format!("<-- {} {} ({:?})", resp.status, endpoint, elapsed),
+                        &headers,
+                        &redactor.body(&resp.body)
+                    )
+                );
+            }
+            Err(e) => info!(
+                target: "http_trace",
+                "<-- {} failed ({:?}): {}",
+                endpoint,
+                elapsed,
+                redactor.text(e)
+            ),
+        }
+    }
+
+    fn redactor(&self) -> Redactor {
+        Redactor::new(vec![self.password.clone()])
+    }
+
     fn do_api_request(&self, builder: reqwest::blocking::RequestBuilder) -> Result<Value, String> {
-        let resp = builder.send().map_err(|err| err.to_string())?;
+        let resp = self.send(builder)?;
         to_resp_json(resp)
     }
 
@@ -68,14 +264,14 @@ impl ApolloAgent {
         &self,
         builder: reqwest::blocking::RequestBuilder,
     ) -> Result<String, String> {
-        let resp = builder.send().map_err(|err| err.to_string())?;
-        resp.text().map_err(|err| err.to_string())
+        let resp = self.send(builder)?;
+        Ok(resp.body)
     }
 
     pub fn get_login_req_token(&self) -> Result<Value, String> {
         let html = self.do_html_request(
             self.client
-                .get("https://asiaauth.mayohr.com/HRM/Account/Login"),
+                .get(self.mayo.url(&self.mayo.auth_url, "/HRM/Account/Login")),
         )?;
 
         let token = Vis::load(html)
@@ -90,7 +286,7 @@ impl ApolloAgent {
             ("companyCode", &self.company),
             ("employeeNo", &self.username),
             ("grant_type", "password"),
-            ("locale", "zh-tw"),
+            ("locale", &self.mayo.locale),
             ("password", &self.password),
             ("red", "https,//apollo.mayohr.com/tube"),
             ("userName", &format!("{}-{}", self.company, self.username)),
@@ -98,7 +294,7 @@ impl ApolloAgent {
 
         self.do_api_request(
             self.client
-                .post("https://asiaauth.mayohr.com/Token")
+                .post(self.mayo.url(&self.mayo.auth_url, "/Token"))
                 .form(payload),
         )
     }
@@ -106,15 +302,20 @@ impl ApolloAgent {
     pub fn check_ticket(&self, auth_code: &str) -> Result<Value, String> {
         self.do_api_request(
             self.client
-                .get("https://linkup-be.mayohr.com/api/auth/checkticket")
+                .get(
+                    self.mayo
+                        .url(&self.mayo.linkup_url, "/api/auth/checkticket"),
+                )
                 .query(&[("code", auth_code)]),
         )
     }
 
     pub fn get_authorized(&self) -> Result<Value, String> {
         self.do_api_request(
-            self.client
-                .get("https://linkup-be.mayohr.com/api/Authorization/GetAuthorized"),
+            self.client.get(
+                self.mayo
+                    .url(&self.mayo.linkup_url, "/api/Authorization/GetAuthorized"),
+            ),
         )
     }
 
@@ -127,7 +328,10 @@ impl ApolloAgent {
 
         self.do_api_request(
             self.client
-                .get("https://pt-be.mayohr.com/api/EmployeeCalendars/scheduling")
+                .get(
+                    self.mayo
+                        .url(&self.mayo.pt_url, "/api/EmployeeCalendars/scheduling"),
+                )
                 .header("Functioncode", "PersonalShiftSchedule")
                 .header("Actioncode", "Default")
                 .query(&[
@@ -142,6 +346,8 @@ impl ApolloAgent {
         year: Option<i32>,
         month: Option<u32>,
     ) -> Result<Vec<WorkdaySchedule>, String> {
+        let _span = info_span!("schedule", ?year, ?month).entered();
+
         let resp = self.get_employee_calendars(year, month)?;
         let calendars = resp["Data"]["Calendars"]
             .as_array()
@@ -154,25 +360,111 @@ impl ApolloAgent {
     }
 
     pub fn get_today_schedule(&self) -> Result<WorkdaySchedule, String> {
-        let today = Local::now().format("%Y-%m-%d").to_string();
-        let schedules = self.get_workday_schedules(None, None)?;
+        self.get_workday_schedule(Local::now().date_naive())
+    }
+
+    pub fn get_workday_schedule(&self, date: NaiveDate) -> Result<WorkdaySchedule, String> {
+        let day = date.format("%Y-%m-%d").to_string();
+        let schedules = self.get_workday_schedules(Some(date.year()), Some(date.month()))?;
 
         schedules
             .into_iter()
-            .find(|x| x.get_date() == today)
-            .ok_or_else(|| format!("Can not find WorkdaySchedule of {}", today))
+            .find(|x| x.get_date() == day)
+            .ok_or_else(|| format!("Can not find WorkdaySchedule of {}", day))
     }
 
     pub fn punch_card(&self, punch_type: PunchType) -> Result<Value, String> {
-        self.do_api_request(
+        let _span = info_span!("punch", %punch_type).entered();
+
+        let url = self.mayo.url(&self.mayo.pt_url, "/api/checkIn/punch/web");
+        let payload = json!({
+            "AttendanceType": punch_type as u8,
+            "IsOverride": false,
+        });
+
+        if self.dry_run {
+            info!(
+                "[dry-run] {} not sent, would POST {} with payload {}",
+                punch_type, url, payload
+            );
+            metrics::record_punch(punch_type, "dry_run");
+            return Ok(json!({
+                "DryRun": true,
+                "Url": url,
+                "Payload": payload,
+            }));
+        }
+
+        let result = self.do_api_request(
             self.client
-                .post("https://pt-be.mayohr.com/api/checkIn/punch/web")
+                .post(url)
                 .header("Functioncode", "PunchCard")
                 .header("Actioncode", "Default")
-                .json(&json!({
-                    "AttendanceType": punch_type as u8,
-                    "IsOverride": false,
-                })),
-        )
+                .json(&payload),
+        );
+        metrics::record_punch(
+            punch_type,
+            if result.is_ok() { "success" } else { "failure" },
+        );
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Agent answering from a cassette in tests/fixtures, see `record_http` for capturing new ones.
+    fn replay_agent(cassette: &str) -> ApolloAgent {
+        let mut agent = ApolloAgent::new("1234", "hunter2", "ACME");
+        agent
+            .replay_http(&format!(
+                "{}/tests/fixtures/{}",
+                env!("CARGO_MANIFEST_DIR"),
+                cassette
+            ))
+            .unwrap();
+        agent
+    }
+
+    #[test]
+    fn test_replay_login_calendar_punch() {
+        let mut agent = replay_agent("login_calendar_punch.json");
+
+        agent.login().unwrap();
+
+        let schedules = agent.get_workday_schedules(Some(2023), Some(9)).unwrap();
+        let lines: Vec<String> = schedules.iter().map(|s| s.to_string()).collect();
+        assert_eq!(
+            lines,
+            [
+                "2023-09-09 休假日 N/A N/A",
+                "2023-09-23 工作日(國慶日補班) 2023-09-23T09:00:00+08:00 2023-09-23T18:00:00+08:00",
+            ]
+        );
+
+        let resp = agent.punch_card(PunchType::PunchIn).unwrap();
+        assert_eq!(resp["Data"]["punchDate"], "2023-09-23T00:59:21.637+00:00");
+    }
+
+    #[test]
+    fn test_replay_punch_failed() {
+        let agent = replay_agent("punch_failed.json");
+
+        let err = agent.punch_card(PunchType::PunchOut).unwrap_err();
+        assert!(err.starts_with("[400][Failed]"));
+        assert!(err.contains("打卡失敗"));
+    }
+
+    #[test]
+    fn test_dry_run_sends_nothing() {
+        // the cassette only answers with a failed punch
+        let mut agent = replay_agent("punch_failed.json");
+        agent.set_dry_run(true);
+
+        let resp = agent.punch_card(PunchType::PunchIn).unwrap();
+        assert_eq!(resp["DryRun"], true);
+        assert_eq!(resp["Payload"]["AttendanceType"], 1);
     }
 }
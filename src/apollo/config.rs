@@ -0,0 +1,1889 @@
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Duration, Local, NaiveTime, TimeZone};
+use lettre::message::Mailbox;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{to_string_pretty, Value};
+use tracing::warn;
+
+use super::agent::PunchType;
+use super::logging::{LogFormat, LogRotation};
+use super::notify::{check_template, NotificationKind};
+use super::presence::parse_cidr;
+use super::secret::resolve_password;
+use super::workday_schedule::WorkdaySchedule;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConfigPayload {
+    pub username: String,
+    pub password: PasswordSource,
+    pub company: String,
+    #[serde(default)]
+    pub catch_up: CatchUpConfig,
+    #[serde(default)]
+    pub planning: PlanningConfig,
+    #[serde(default)]
+    pub notifications: NotificationConfig,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub telegram: Option<TelegramConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mqtt: Option<MqttConfig>,
+    #[serde(default)]
+    pub hooks: HooksConfig,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence: Option<PresenceConfig>,
+    #[serde(default)]
+    pub punch: PunchConfig,
+    #[serde(default)]
+    pub mayo: MayoConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+}
+
+impl ConfigPayload {
+    /// The plain password, fetched from wherever `password` points to.
+    pub fn resolve_password(&self) -> Result<String, String> {
+        resolve_password(&self.password, &self.username, &self.company)
+    }
+
+    /// What is wrong with the values, each naming the key, e.g. "mqtt.port: must not be 0".
+    /// Values are checked without connecting anywhere.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        let mut check = |ok: bool, key: String, problem: &str| {
+            if !ok {
+                problems.push(format!("{}: {}", key, problem));
+            }
+        };
+        let is_url = |url: &str| {
+            reqwest::Url::parse(url).is_ok_and(|v| v.scheme() == "http" || v.scheme() == "https")
+        };
+
+        check(
+            !self.username.is_empty(),
+            "username".into(),
+            "must not be empty",
+        );
+        check(
+            !self.company.is_empty(),
+            "company".into(),
+            "must not be empty",
+        );
+        let password_set = match &self.password {
+            PasswordSource::Plain(v) => !v.is_empty(),
+            PasswordSource::Env { env } => !env.is_empty(),
+            PasswordSource::Command { command } => !command.is_empty(),
+            PasswordSource::File { file } => !file.is_empty(),
+            PasswordSource::Keyring { keyring } => !keyring.is_empty(),
+            PasswordSource::Encrypted { encrypted } => !encrypted.is_empty(),
+        };
+        check(password_set, "password".into(), "must not be empty");
+
+        check(
+            self.planning.minutes_before_shift <= 24 * 60,
+            "planning.minutes_before_shift".into(),
+            "must be at most 1440",
+        );
+        check(
+            self.punch.jitter_seconds <= 3600,
+            "punch.jitter_seconds".into(),
+            "must be at most 3600",
+        );
+        check(
+            self.hooks.timeout_seconds > 0,
+            "hooks.timeout_seconds".into(),
+            "must not be 0",
+        );
+
+        for (key, url) in [
+            ("mayo.auth_url", &self.mayo.auth_url),
+            ("mayo.linkup_url", &self.mayo.linkup_url),
+            ("mayo.pt_url", &self.mayo.pt_url),
+        ] {
+            check(is_url(url), key.into(), "must be an http(s) URL");
+        }
+        check(
+            !self.mayo.locale.is_empty(),
+            "mayo.locale".into(),
+            "must not be empty",
+        );
+
+        for (i, webhook) in self.notifications.webhooks.iter().enumerate() {
+            let key = format!("notifications.webhooks[{}]", i);
+            check(
+                is_url(&webhook.url),
+                format!("{}.url", key),
+                "must be an http(s) URL",
+            );
+            if let Some(Err(e)) = webhook.body_template.as_deref().map(check_template) {
+                check(false, format!("{}.body_template", key), &e);
+            }
+        }
+        for (i, email) in self.notifications.emails.iter().enumerate() {
+            let key = format!("notifications.emails[{}]", i);
+            check(
+                !email.host.is_empty(),
+                format!("{}.host", key),
+                "must not be empty",
+            );
+            check(
+                email.from.parse::<Mailbox>().is_ok(),
+                format!("{}.from", key),
+                "must be an email address",
+            );
+            check(
+                !email.to.is_empty(),
+                format!("{}.to", key),
+                "must not be empty",
+            );
+            for (j, to) in email.to.iter().enumerate() {
+                check(
+                    to.parse::<Mailbox>().is_ok(),
+                    format!("{}.to[{}]", key, j),
+                    "must be an email address",
+                );
+            }
+        }
+
+        if let Some(telegram) = &self.telegram {
+            check(
+                !telegram.token.is_empty(),
+                "telegram.token".into(),
+                "must not be empty",
+            );
+            check(
+                is_url(&telegram.api_url),
+                "telegram.api_url".into(),
+                "must be an http(s) URL",
+            );
+            check(
+                !telegram.allowed_chats.is_empty(),
+                "telegram.allowed_chats".into(),
+                "must not be empty",
+            );
+        }
+
+        if let Some(mqtt) = &self.mqtt {
+            check(
+                !mqtt.host.is_empty(),
+                "mqtt.host".into(),
+                "must not be empty",
+            );
+            check(mqtt.port > 0, "mqtt.port".into(), "must not be 0");
+            check(
+                !mqtt.topic_prefix.is_empty() && !mqtt.topic_prefix.contains(['+', '#']),
+                "mqtt.topic_prefix".into(),
+                "must be a topic without wildcards",
+            );
+        }
+
+        if let Some(presence) = &self.presence {
+            match &presence.check {
+                PresenceCheck::Reachable { address } => check(
+                    address.rsplit_once(':').is_some_and(|(host, port)| {
+                        !host.is_empty() && port.parse::<u16>().is_ok()
+                    }),
+                    "presence.address".into(),
+                    "must be like host:port",
+                ),
+                PresenceCheck::Subnet { cidr } => {
+                    if let Err(e) = parse_cidr(cidr) {
+                        check(false, "presence.cidr".into(), &e);
+                    }
+                }
+                PresenceCheck::Command { command } => check(
+                    !command.is_empty(),
+                    "presence.command".into(),
+                    "must not be empty",
+                ),
+            }
+            check(
+                presence.interval_seconds > 0,
+                "presence.interval_seconds".into(),
+                "must not be 0",
+            );
+        }
+
+        if let Some(max_files) = self.logging.max_files {
+            check(max_files > 0, "logging.max_files".into(), "must not be 0");
+        }
+
+        problems
+    }
+}
+
+/// Where the password comes from, a plain string is the password itself.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(
+    untagged,
+    expecting = r#"a password, or {"env": ...}, {"command": ...}, {"file": ...}, {"keyring": ...} or {"encrypted": ...}"#
+)]
+pub enum PasswordSource {
+    Plain(String),
+    /// environment variable holding the password
+    Env {
+        env: String,
+    },
+    /// shell command printing the password on its first line, e.g. "pass show mayo"
+    Command {
+        command: String,
+    },
+    /// file holding the password
+    File {
+        file: String,
+    },
+    /// Secret Service service name, the entry is named <username>@<company>
+    Keyring {
+        keyring: String,
+    },
+    /// armored age file encrypted with a passphrase, written by `config encrypt`
+    Encrypted {
+        encrypted: String,
+    },
+}
+
+/// How auto punches are timed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PunchConfig {
+    /// punch in up to this long before the shift starts and punch out up to this long
+    /// after it ends, at random, 0 punches right at the shift times
+    #[serde(default = "default_jitter_seconds")]
+    pub jitter_seconds: u32,
+}
+
+fn default_jitter_seconds() -> u32 {
+    60
+}
+
+impl Default for PunchConfig {
+    fn default() -> Self {
+        PunchConfig {
+            jitter_seconds: default_jitter_seconds(),
+        }
+    }
+}
+
+/// Mayo servers, only worth changing for a stand-in or when Mayo moves them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MayoConfig {
+    #[serde(default = "default_mayo_auth_url")]
+    pub auth_url: String,
+    #[serde(default = "default_mayo_linkup_url")]
+    pub linkup_url: String,
+    #[serde(default = "default_mayo_pt_url")]
+    pub pt_url: String,
+    /// language of Mayo's messages, e.g. "en-us"
+    #[serde(default = "default_mayo_locale")]
+    pub locale: String,
+}
+
+fn default_mayo_auth_url() -> String {
+    "https://asiaauth.mayohr.com".to_string()
+}
+
+fn default_mayo_linkup_url() -> String {
+    "https://linkup-be.mayohr.com".to_string()
+}
+
+fn default_mayo_pt_url() -> String {
+    "https://pt-be.mayohr.com".to_string()
+}
+
+fn default_mayo_locale() -> String {
+    "zh-tw".to_string()
+}
+
+impl Default for MayoConfig {
+    fn default() -> Self {
+        MayoConfig {
+            auth_url: default_mayo_auth_url(),
+            linkup_url: default_mayo_linkup_url(),
+            pt_url: default_mayo_pt_url(),
+            locale: default_mayo_locale(),
+        }
+    }
+}
+
+impl MayoConfig {
+    /// `path` on the server at `base_url`.
+    pub fn url(&self, base_url: &str, path: &str) -> String {
+        format!("{}{}", base_url.trim_end_matches('/'), path)
+    }
+}
+
+/// Logging defaults, the --log-* options override them. Process wide, so only read from the
+/// top level of the config.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LoggingConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<LogFormat>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotation: Option<LogRotation>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_files: Option<usize>,
+}
+
+/// What auto punch should do when it starts after the arranged punch time.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub enum CatchUpPolicy {
+    /// punch immediately if it is still within `minutes` after the shift time, skip otherwise
+    PunchWithin { minutes: u32 },
+    /// never catch up
+    Skip,
+    /// never catch up, but always tell the user a manual punch is needed
+    Notify,
+}
+
+impl Default for CatchUpPolicy {
+    fn default() -> Self {
+        CatchUpPolicy::PunchWithin { minutes: 0 }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CatchUpAction {
+    PunchNow,
+    Skip,
+    Notify,
+}
+
+impl CatchUpPolicy {
+    pub fn decide(&self, shift_time: &DateTime<Local>, now: &DateTime<Local>) -> CatchUpAction {
+        match self {
+            CatchUpPolicy::PunchWithin { minutes } => {
+                if *now <= *shift_time + Duration::minutes(*minutes as i64) {
+                    CatchUpAction::PunchNow
+                } else {
+                    CatchUpAction::Skip
+                }
+            }
+            CatchUpPolicy::Skip => CatchUpAction::Skip,
+            CatchUpPolicy::Notify => CatchUpAction::Notify,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct CatchUpConfig {
+    #[serde(default)]
+    pub punch_in: CatchUpPolicy,
+    #[serde(default)]
+    pub punch_out: CatchUpPolicy,
+}
+
+/// When the daemon wakes up to fetch the schedule and arrange the punches of a day.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PlanningConfig {
+    /// wake up this many minutes before the shift starts
+    #[serde(default = "default_minutes_before_shift")]
+    pub minutes_before_shift: u32,
+    /// fixed wake up time ("HH:MM"), overrides `minutes_before_shift` when set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<NaiveTime>,
+    /// wake up time ("HH:MM") for days without shift
+    #[serde(default = "default_fallback_time")]
+    pub fallback_time: NaiveTime,
+}
+
+fn default_minutes_before_shift() -> u32 {
+    120
+}
+
+fn default_fallback_time() -> NaiveTime {
+    NaiveTime::from_hms_opt(7, 0, 0).unwrap()
+}
+
+impl Default for PlanningConfig {
+    fn default() -> Self {
+        PlanningConfig {
+            minutes_before_shift: default_minutes_before_shift(),
+            time: None,
+            fallback_time: default_fallback_time(),
+        }
+    }
+}
+
+impl PlanningConfig {
+    pub fn get_planning_time(&self, schedule: &WorkdaySchedule) -> DateTime<Local> {
+        let at = |time: NaiveTime| {
+            Local
+                .from_local_datetime(&schedule.get_naive_date().and_time(time))
+                .earliest()
+                .unwrap()
+        };
+
+        if let Some(time) = self.time {
+            return at(time);
+        }
+
+        match schedule.get_shift_time(PunchType::PunchIn) {
+            Some(t) => t - Duration::minutes(self.minutes_before_shift as i64),
+            None => at(self.fallback_time),
+        }
+    }
+
+    /// The planning time of `schedule` if it is still ahead of `now`, None when it has passed
+    /// and the day should be planned right away.
+    pub fn get_wake_up_time(
+        &self,
+        schedule: &WorkdaySchedule,
+        now: &DateTime<Local>,
+    ) -> Option<DateTime<Local>> {
+        Some(self.get_planning_time(schedule)).filter(|t| t > now)
+    }
+}
+
+/// How to tell whether the user is at work.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "check", rename_all = "snake_case")]
+pub enum PresenceCheck {
+    /// a TCP connection to `address` ("host:port") succeeds
+    Reachable { address: String },
+    /// the address this machine uses to reach `cidr` (e.g. "192.168.1.0/24") is inside it
+    Subnet { cidr: String },
+    /// `command` run with `sh -c` exits with 0
+    Command { command: String },
+}
+
+/// Checked before every auto punch, a punch without presence waits and is finally skipped.
+/// Manual punches are not checked.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PresenceConfig {
+    #[serde(flatten)]
+    pub check: PresenceCheck,
+    /// keep checking this long before giving up, 0 gives up on the first failed check
+    #[serde(default)]
+    pub wait_minutes: u32,
+    #[serde(default = "default_presence_interval_seconds")]
+    pub interval_seconds: u32,
+}
+
+fn default_presence_interval_seconds() -> u32 {
+    60
+}
+
+/// Shell commands run with `sh -c` around the punches of the daemon and of the punch-in and
+/// punch-out subcommands, see `hooks::HookContext` for what they receive.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HooksConfig {
+    /// a non-zero exit vetoes the punch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before_punch_in: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after_punch_in: Option<String>,
+    /// a non-zero exit vetoes the punch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before_punch_out: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after_punch_out: Option<String>,
+    /// runs instead of the after hook when the punch failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_failure: Option<String>,
+    /// hooks still running after this are killed, a killed before hook vetoes the punch
+    #[serde(default = "default_hook_timeout_seconds")]
+    pub timeout_seconds: u64,
+}
+
+fn default_hook_timeout_seconds() -> u64 {
+    60
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        HooksConfig {
+            before_punch_in: None,
+            after_punch_in: None,
+            before_punch_out: None,
+            after_punch_out: None,
+            on_failure: None,
+            timeout_seconds: default_hook_timeout_seconds(),
+        }
+    }
+}
+
+/// Where the daemon reports punch outcomes and anomalies.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NotificationConfig {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub webhooks: Vec<WebhookConfig>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub emails: Vec<EmailConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// extra request headers, e.g. Authorization
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// JSON body with `{{field}}` placeholders, the notification itself is posted when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_template: Option<String>,
+    /// attempts after the first one failed
+    #[serde(default = "default_webhook_retries")]
+    pub retries: u32,
+    /// notifications to send, all of them but the daily summary when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<NotificationKind>,
+    /// also send the planned punch times every work day
+    #[serde(default)]
+    pub daily_summary: bool,
+}
+
+fn default_webhook_retries() -> u32 {
+    3
+}
+
+/// How the SMTP connection is secured.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpSecurity {
+    /// upgrade a plain connection with STARTTLS, port 587 by default
+    #[default]
+    Starttls,
+    /// TLS from the start, port 465 by default
+    Tls,
+    /// no encryption, port 25 by default, only meant for local relays
+    None,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmailConfig {
+    pub host: String,
+    /// defaults to the usual port of `security`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+    /// notifications to send, all of them but the daily summary when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<NotificationKind>,
+    /// also send the planned punch times every work day
+    #[serde(default)]
+    pub daily_summary: bool,
+}
+
+/// Telegram bot taking commands in the `bot` subcommand, and receiving notifications of the daemon.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TelegramConfig {
+    pub token: String,
+    /// Bot API server, change it for a local stand-in
+    #[serde(default = "default_telegram_api_url")]
+    pub api_url: String,
+    /// chats allowed to send commands, notifications are sent to all of them
+    pub allowed_chats: Vec<i64>,
+    /// notifications to send, all of them but the daily summary when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<NotificationKind>,
+    /// also send the planned punch times every work day
+    #[serde(default)]
+    pub daily_summary: bool,
+}
+
+fn default_telegram_api_url() -> String {
+    "https://api.telegram.org".to_string()
+}
+
+/// MQTT broker the daemon publishes its state to and takes commands from,
+/// changes need a restart of the daemon.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// topics are `<topic_prefix>/<node_id>/...`
+    #[serde(default = "default_mqtt_topic_prefix")]
+    pub topic_prefix: String,
+    /// defaults to apollo_<company>_<username>
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<String>,
+    /// publish Home Assistant discovery payloads, on by default
+    #[serde(default = "default_mqtt_discovery")]
+    pub discovery: bool,
+    /// Home Assistant discovery prefix
+    #[serde(default = "default_mqtt_discovery_prefix")]
+    pub discovery_prefix: String,
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_topic_prefix() -> String {
+    "apollo".to_string()
+}
+
+fn default_mqtt_discovery() -> bool {
+    true
+}
+
+fn default_mqtt_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+/// One employee of a multi-account config, punched by `auto-punch --all`. It is written
+/// like a single-account config with a `name` added.
+#[derive(Debug)]
+pub struct AccountConfig {
+    /// names the account's state, lock and socket files, e.g. team.alice.sock
+    pub name: String,
+    pub config: ConfigPayload,
+}
+
+#[derive(Debug)]
+pub struct MultiAccountConfig {
+    pub accounts: Vec<AccountConfig>,
+}
+
+/// `MultiAccountConfig` as read, its accounts are deserialized one by one afterwards, as
+/// `#[serde(flatten)]` would lose the paths of their errors and unknown keys.
+#[derive(Deserialize)]
+struct RawMultiAccountConfig {
+    accounts: Vec<serde_json::Map<String, Value>>,
+    /// read by `load_logging_config`, checked here
+    #[serde(default, rename = "logging")]
+    _logging: LoggingConfig,
+}
+
+/// Where a daemon's config is loaded from, and re-loaded on SIGHUP.
+#[derive(Debug, Clone)]
+pub enum ConfigSource {
+    File(String),
+    /// one account of a multi-account config
+    Account {
+        config_name: String,
+        account: String,
+    },
+}
+
+impl ConfigSource {
+    /// Name the state, lock and socket filenames are derived from.
+    pub fn name(&self) -> String {
+        match self {
+            ConfigSource::File(config_name) => config_name.clone(),
+            ConfigSource::Account {
+                config_name,
+                account,
+            } => get_sibling_filename(config_name, &format!(".{}", account)),
+        }
+    }
+
+    pub fn load(&self) -> Result<ConfigPayload, String> {
+        match self {
+            ConfigSource::File(config_name) => load_config_file(config_name),
+            ConfigSource::Account {
+                config_name,
+                account,
+            } => load_accounts_file(config_name)?
+                .into_iter()
+                .find(|a| a.name == *account)
+                .map(|a| a.config)
+                .ok_or_else(|| {
+                    format!(
+                        "no account {} in {}",
+                        account,
+                        get_config_filename(config_name)
+                    )
+                }),
+        }
+    }
+}
+
+/// Config used when neither --config, --profile nor APOLLO_CONFIG is given.
+const DEFAULT_CONFIG_NAME: &str = "config";
+
+/// Extensions of config files, tried in this order for config names without one.
+const CONFIG_EXTENSIONS: &[&str] = &["json", "toml", "yaml", "yml"];
+
+/// Config file format, told by the extension.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    fn of(config_filename: &str) -> Self {
+        match Path::new(config_filename)
+            .extension()
+            .and_then(|v| v.to_str())
+        {
+            Some("toml") => ConfigFormat::Toml,
+            Some("yaml") | Some("yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Json,
+        }
+    }
+
+    /// Parses `text`, errors name the offending key. Keys nothing reads are pushed into `unknown`.
+    fn parse<T: DeserializeOwned>(
+        self,
+        text: &str,
+        unknown: &mut Vec<String>,
+    ) -> Result<T, String> {
+        match self {
+            ConfigFormat::Json => {
+                let mut deserializer = serde_json::Deserializer::from_str(text);
+                let config = deserialize_tracked(&mut deserializer, "", unknown)?;
+                deserializer.end().map_err(|e| e.to_string())?;
+                Ok(config)
+            }
+            ConfigFormat::Toml => deserialize_tracked(toml::Deserializer::new(text), "", unknown),
+            ConfigFormat::Yaml => {
+                deserialize_tracked(serde_yaml::Deserializer::from_str(text), "", unknown)
+            }
+        }
+    }
+
+    fn to_string<T: Serialize>(self, config: &T) -> Result<String, String> {
+        match self {
+            ConfigFormat::Json => to_string_pretty(config).map_err(|e| e.to_string()),
+            ConfigFormat::Toml => toml::to_string_pretty(config).map_err(|e| e.to_string()),
+            ConfigFormat::Yaml => serde_yaml::to_string(config).map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Replaces the `password` of the config in `text`, or of the accounts at the given
+    /// indexes when `multi_account`, leaving the rest as the user wrote it. TOML and YAML keep
+    /// their comments too, unless the YAML is written in a way `yaml_replace_password` can't edit.
+    fn replace_passwords(
+        self,
+        text: &str,
+        multi_account: bool,
+        passwords: &[(usize, PasswordSource)],
+    ) -> Result<String, String> {
+        match self {
+            ConfigFormat::Json => {
+                let mut config: Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+                for (i, password) in passwords {
+                    let account = match multi_account {
+                        true => &mut config["accounts"][i],
+                        false => &mut config,
+                    };
+                    account["password"] = serde_json::to_value(password).unwrap();
+                }
+                to_string_pretty(&config).map_err(|e| e.to_string())
+            }
+            ConfigFormat::Toml => {
+                let mut config: toml_edit::DocumentMut =
+                    text.parse().map_err(|e| format!("{}", e))?;
+                for (i, password) in passwords {
+                    let account: Option<&mut dyn toml_edit::TableLike> = match multi_account {
+                        // [[accounts]] sections, or an array of inline tables
+                        true => match &mut config["accounts"] {
+                            toml_edit::Item::ArrayOfTables(accounts) => accounts
+                                .get_mut(*i)
+                                .map(|v| v as &mut dyn toml_edit::TableLike),
+                            toml_edit::Item::Value(toml_edit::Value::Array(accounts)) => accounts
+                                .get_mut(*i)
+                                .and_then(|v| v.as_inline_table_mut())
+                                .map(|v| v as &mut dyn toml_edit::TableLike),
+                            _ => None,
+                        },
+                        false => Some(config.as_table_mut()),
+                    };
+                    let item = account
+                        .and_then(|v| v.get_mut("password"))
+                        .ok_or_else(|| format!("no password of account {}", i))?;
+
+                    let mut value = password
+                        .serialize(toml_edit::ser::ValueSerializer::new())
+                        .map_err(|e| e.to_string())?;
+                    // keeps e.g. a comment at the end of the line
+                    if let Some(old) = item.as_value() {
+                        *value.decor_mut() = old.decor().clone();
+                    }
+                    *item = toml_edit::Item::Value(value);
+                }
+                Ok(config.to_string())
+            }
+            ConfigFormat::Yaml => {
+                let mut config: serde_yaml::Value =
+                    serde_yaml::from_str(text).map_err(|e| e.to_string())?;
+                let mut edited = Some(text.to_string());
+                for (i, password) in passwords {
+                    let account = match multi_account {
+                        true => &mut config["accounts"][i],
+                        false => &mut config,
+                    };
+                    let value = serde_yaml::to_value(password).unwrap();
+                    edited = edited.and_then(|text| {
+                        yaml_replace_password(&text, multi_account.then_some(*i), &value)
+                    });
+                    account["password"] = value;
+                }
+
+                // the edited lines must read the same as the config edited as a value
+                let parsed = edited
+                    .as_deref()
+                    .map(serde_yaml::from_str::<serde_yaml::Value>);
+                match (edited, parsed) {
+                    (Some(text), Some(Ok(parsed))) if parsed == config => Ok(text),
+                    _ => {
+                        warn!(
+                            "can't replace the passwords in place, the YAML config is rewritten without its comments"
+                        );
+                        serde_yaml::to_string(&config).map_err(|e| e.to_string())
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn yaml_indent(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+/// Whether `line` has nothing but a comment or whitespace.
+fn yaml_is_blank(line: &str) -> bool {
+    let line = line.trim();
+    line.is_empty() || line.starts_with('#')
+}
+
+/// Replaces the block style `password` line of the top level mapping in `text`, or of
+/// the `account`th item of the top level `accounts` list, along with the lines of its value.
+/// Returns None when it can't be found, flow style and anchors are left to the caller.
+fn yaml_replace_password(
+    text: &str,
+    account: Option<usize>,
+    password: &serde_yaml::Value,
+) -> Option<String> {
+    let mut lines: Vec<String> = text.split('\n').map(|v| v.to_string()).collect();
+
+    // lines of the mapping holding the password, its first line and the indent of its keys
+    let (first, end, indent) = match account {
+        None => (0, lines.len(), 0),
+        Some(account) => {
+            let start = lines
+                .iter()
+                .position(|v| v.strip_prefix("accounts:").is_some_and(yaml_is_blank))?
+                + 1;
+            let mut items = vec![];
+            let mut item_indent = None;
+            let mut end = lines.len();
+            for (i, line) in lines.iter().enumerate().skip(start) {
+                if yaml_is_blank(line) {
+                    continue;
+                }
+                let indent = yaml_indent(line);
+                let is_item = line[indent..] == *"-" || line[indent..].starts_with("- ");
+                match item_indent {
+                    None if is_item => item_indent = Some(indent),
+                    None => return None,
+                    Some(v) if indent < v || (indent == v && !is_item) => {
+                        end = i;
+                        break;
+                    }
+                    Some(_) => {}
+                }
+                if is_item && Some(indent) == item_indent {
+                    items.push(i);
+                }
+            }
+
+            let first = *items.get(account)?;
+            let end = items.get(account + 1).copied().unwrap_or(end);
+            let item = &lines[first];
+            let after_dash = yaml_indent(item) + 1;
+            let indent = if item[after_dash..].trim().is_empty() {
+                // "-" alone, the keys start on the next lines
+                lines[first + 1..end]
+                    .iter()
+                    .find(|v| !yaml_is_blank(v))
+                    .map(|v| yaml_indent(v))?
+            } else {
+                after_dash + yaml_indent(&item[after_dash..])
+            };
+            (first, end, indent)
+        }
+    };
+
+    let key = (first..end).find(|&i| {
+        let line = &lines[i];
+        (i == first || yaml_indent(line) == indent)
+            && line
+                .get(indent..)
+                .and_then(|v| v.strip_prefix("password:"))
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with([' ', '\t']))
+    })?;
+
+    // the value goes on as long as the lines are indented deeper than the key
+    let mut value_end = key + 1;
+    while value_end < end
+        && (lines[value_end].trim().is_empty() || yaml_indent(&lines[value_end]) > indent)
+    {
+        value_end += 1;
+    }
+    while value_end > key + 1 && lines[value_end - 1].trim().is_empty() {
+        value_end -= 1;
+    }
+
+    // a comment after a plain scalar is kept, a quoted one may hold a #
+    let old_value = lines[key][indent + "password:".len()..].trim();
+    let comment = match old_value.starts_with(['"', '\'']) {
+        true => None,
+        false if old_value.starts_with('#') => Some(old_value),
+        false => old_value.find(" #").map(|i| old_value[i..].trim()),
+    }
+    .map_or(String::new(), |v| format!(" {}", v));
+
+    let rendered = serde_yaml::to_string(password).ok()?;
+    let mut rendered = rendered.trim_end_matches('\n').lines();
+    let prefix = &lines[key][..indent];
+    let mut replacement = vec![];
+    if password.is_mapping() {
+        replacement.push(format!("{}password:{}", prefix, comment));
+        let nested = " ".repeat(indent + 2);
+        replacement.extend(rendered.map(|v| format!("{}{}", nested, v)));
+    } else {
+        replacement.push(format!(
+            "{}password: {}{}",
+            prefix,
+            rendered.next()?,
+            comment
+        ));
+        // block scalar lines come indented already
+        let nested = " ".repeat(indent);
+        replacement.extend(rendered.map(|v| format!("{}{}", nested, v)));
+    }
+
+    lines.splice(key..value_end, replacement);
+    Some(lines.join("\n"))
+}
+
+/// Deserializes with errors and unknown keys named by their path, below `prefix` if not empty.
+fn deserialize_tracked<'de, D, T>(
+    deserializer: D,
+    prefix: &str,
+    unknown: &mut Vec<String>,
+) -> Result<T, String>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    let join = |path: String| match (prefix, path.as_str()) {
+        ("", _) => path,
+        (_, ".") => prefix.to_string(),
+        _ => format!("{}.{}", prefix, path),
+    };
+
+    let mut track = |path: serde_ignored::Path| unknown.push(join(path.to_string()));
+    let deserializer = serde_ignored::Deserializer::new(deserializer, &mut track);
+
+    serde_path_to_error::deserialize(deserializer).map_err(|e| match join(e.path().to_string()) {
+        path if path == "." => e.inner().to_string(),
+        path => format!("{}: {}", path, e.inner()),
+    })
+}
+
+/// Reads the config file in the format of its extension.
+fn read_config_file<T: DeserializeOwned>(
+    config_filename: &str,
+    unknown: &mut Vec<String>,
+) -> Result<T, String> {
+    let text = fs::read_to_string(config_filename)
+        .map_err(|e| format!("can't open {}\nreason: {}", config_filename, e))?;
+
+    ConfigFormat::of(config_filename)
+        .parse(&text, unknown)
+        .map_err(|e| format!("can't parse {}\nreason: {}", config_filename, e))
+}
+
+/// Where profiles live: $XDG_CONFIG_HOME/apollo, or ~/.config/apollo.
+pub fn get_config_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        // the spec says relative paths are invalid and must be ignored
+        .filter(|p| p.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|p| p.join("apollo"))
+}
+
+/// Letters, digits, - and _ only, so the name is safe in filenames.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Picks the config by, in order: `config` (--config), `profile` in `config_dir`,
+/// `env_config` (APOLLO_CONFIG), config in `cwd` when it exists, and at last config in
+/// `config_dir`. Names without extension are completed by `get_config_filename`.
+pub fn resolve_config_name(
+    config: Option<&str>,
+    profile: Option<&str>,
+    env_config: Option<&str>,
+    cwd: &Path,
+    config_dir: Option<&Path>,
+) -> Result<String, String> {
+    let in_config_dir = |filename: &str| {
+        config_dir
+            .map(|dir| dir.join(filename).to_string_lossy().to_string())
+            .ok_or_else(|| {
+                "can't locate the config directory, set XDG_CONFIG_HOME or HOME, or pass --config"
+                    .to_string()
+            })
+    };
+
+    if let Some(config) = config {
+        return Ok(config.to_string());
+    }
+    if let Some(profile) = profile {
+        if !is_valid_name(profile) {
+            return Err(format!(
+                "invalid profile name {:?}, use letters, digits, - and _ only",
+                profile
+            ));
+        }
+        return in_config_dir(profile);
+    }
+    if let Some(config) = env_config.filter(|v| !v.is_empty()) {
+        return Ok(config.to_string());
+    }
+    if CONFIG_EXTENSIONS.iter().any(|ext| {
+        cwd.join(format!("{}.{}", DEFAULT_CONFIG_NAME, ext))
+            .exists()
+    }) {
+        return Ok(DEFAULT_CONFIG_NAME.to_string());
+    }
+    in_config_dir(DEFAULT_CONFIG_NAME)
+}
+
+/// Profiles in `config_dir` as (name, config filename), sorted by name.
+pub fn list_profiles(config_dir: &Path) -> Result<Vec<(String, String)>, String> {
+    let entries = match fs::read_dir(config_dir) {
+        Ok(v) => v,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(format!("can't list {}: {}", config_dir.display(), e)),
+    };
+
+    let mut profiles = vec![];
+    for entry in entries {
+        let path = entry
+            .map_err(|e| format!("can't list {}: {}", config_dir.display(), e))?
+            .path();
+        let filename = path.file_name().unwrap_or_default().to_string_lossy();
+        // state files sit next to the configs
+        if let Some(name) = CONFIG_EXTENSIONS
+            .iter()
+            .find_map(|ext| filename.strip_suffix(&format!(".{}", ext)))
+            .filter(|name| is_valid_name(name))
+        {
+            profiles.push((name.to_string(), path.to_string_lossy().to_string()));
+        }
+    }
+    profiles.sort();
+
+    Ok(profiles)
+}
+
+fn has_config_extension(config_name: &str) -> bool {
+    Path::new(config_name)
+        .extension()
+        .and_then(|v| v.to_str())
+        .is_some_and(|ext| CONFIG_EXTENSIONS.contains(&ext))
+}
+
+/// `config_name` itself when it has a config extension, otherwise the first existing file
+/// of `config_name` with one, config_name.json when there is none.
+pub fn get_config_filename(config_name: &String) -> String {
+    if has_config_extension(config_name) {
+        return config_name.clone();
+    }
+
+    CONFIG_EXTENSIONS
+        .iter()
+        .map(|ext| format!("{}.{}", config_name, ext))
+        .find(|filename| Path::new(filename).exists())
+        .unwrap_or_else(|| format!("{}.json", config_name))
+}
+
+/// File next to the config file, with the extension replaced by `suffix`.
+fn get_sibling_filename(config_name: &String, suffix: &str) -> String {
+    let config_filename = get_config_filename(config_name);
+    let (stem, _) = config_filename.rsplit_once('.').unwrap();
+    format!("{}{}", stem, suffix)
+}
+
+/// State file keeping the day plan of the auto punch daemon.
+pub fn get_state_filename(config_name: &String) -> String {
+    get_sibling_filename(config_name, ".state.json")
+}
+
+/// Lock file allowing only one auto punch daemon per config.
+pub fn get_lock_filename(config_name: &String) -> String {
+    get_sibling_filename(config_name, ".lock")
+}
+
+/// Password file written by `init --store file`.
+pub fn get_password_filename(config_name: &String) -> String {
+    get_sibling_filename(config_name, ".password")
+}
+
+/// Unix socket the auto punch daemon listens on for `ctl` commands.
+pub fn get_socket_filename(config_name: &String) -> String {
+    get_sibling_filename(config_name, ".sock")
+}
+
+pub fn write_config_file<T: Serialize>(config_name: &String, config: &T) -> Result<(), String> {
+    write_config_text(config_name, &format_config(config_name, config)?)
+}
+
+/// Writes `text` into the config file atomically, a failure never leaves it half written.
+fn write_config_text(config_name: &String, text: &str) -> Result<(), String> {
+    let config_filename = get_config_filename(config_name);
+    let tmp_filename = format!("{}.tmp", config_filename);
+
+    if let Some(dir) = Path::new(&config_filename).parent() {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("can't create {}\nreason: {}", dir.display(), e))?;
+    }
+    // it holds credentials, owner only
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_filename)
+        .and_then(|mut file| {
+            // the mode only applies to new files, a leftover one may have another
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+            file.write_all(text.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp_filename, &config_filename))
+        .map_err(|e| {
+            let _ = fs::remove_file(&tmp_filename);
+            format!("can't write {}\nreason: {}", config_filename, e)
+        })
+}
+
+/// `config` in the format of the config file.
+pub fn format_config<T: Serialize>(config_name: &String, config: &T) -> Result<String, String> {
+    ConfigFormat::of(&get_config_filename(config_name)).to_string(config)
+}
+
+/// Loads and validates a single-account config, unknown keys are warned about.
+pub fn load_config_file(config_name: &String) -> Result<ConfigPayload, String> {
+    let (config, unknown) = load_checked_config_file(config_name)?;
+    for key in unknown {
+        warn!(
+            "unknown key {} in {}, ignored",
+            key,
+            get_config_filename(config_name)
+        );
+    }
+
+    Ok(config)
+}
+
+fn load_checked_config_file(config_name: &String) -> Result<(ConfigPayload, Vec<String>), String> {
+    let config_filename = get_config_filename(config_name);
+    if !Path::new(&config_filename).exists() {
+        return Err(format!(
+            r#"can't open {}
+
+if this is your first time usage, try call init subcommand first,
+        "#,
+            &config_filename
+        ));
+    }
+
+    let mut unknown = vec![];
+    let config: ConfigPayload = read_config_file(&config_filename, &mut unknown)?;
+    let problems = config.validate();
+    if !problems.is_empty() {
+        return Err(format!(
+            "invalid {}\n{}",
+            &config_filename,
+            problems.join("\n")
+        ));
+    }
+
+    Ok((config, unknown))
+}
+
+/// The config file as plain JSON, single or multi-account alike.
+pub fn load_config_value(config_name: &String) -> Result<Value, String> {
+    read_config_file(&get_config_filename(config_name), &mut vec![])
+}
+
+/// The `logging` section of the config, defaults when it can't be read, the config is
+/// loaded again later and the error reported then.
+pub fn load_logging_config(config_name: &String) -> LoggingConfig {
+    #[derive(Deserialize)]
+    struct LoggingOnly {
+        #[serde(default)]
+        logging: LoggingConfig,
+    }
+
+    read_config_file::<LoggingOnly>(&get_config_filename(config_name), &mut vec![])
+        .map(|v| v.logging)
+        .unwrap_or_default()
+}
+
+fn is_multi_account(config_name: &String) -> Result<bool, String> {
+    Ok(load_config_value(config_name)?.get("accounts").is_some())
+}
+
+/// Loads and validates the config, single or multi-account, returns the keys nothing reads.
+pub fn check_config_file(config_name: &String) -> Result<Vec<String>, String> {
+    if is_multi_account(config_name)? {
+        load_multi_account_file(config_name).map(|(_, unknown)| unknown)
+    } else {
+        load_checked_config_file(config_name).map(|(_, unknown)| unknown)
+    }
+}
+
+/// Rewrites the config with the password of every account passed through `f`, which
+/// returns None to keep a password as is. Returns how many passwords were changed.
+pub fn update_passwords<F>(config_name: &String, mut f: F) -> Result<usize, String>
+where
+    F: FnMut(&PasswordSource) -> Result<Option<PasswordSource>, String>,
+{
+    // loaded to validate the config, but only the passwords are written back, defaults
+    // are not spelled out and unknown keys stay
+    let multi_account = is_multi_account(config_name)?;
+    let passwords = if multi_account {
+        load_multi_account_file(config_name)?
+            .0
+            .accounts
+            .into_iter()
+            .map(|v| v.config.password)
+            .collect()
+    } else {
+        vec![load_config_file(config_name)?.password]
+    };
+
+    let mut changed = vec![];
+    for (i, password) in passwords.iter().enumerate() {
+        if let Some(v) = f(password)? {
+            changed.push((i, v));
+        }
+    }
+    if changed.is_empty() {
+        return Ok(0);
+    }
+
+    let config_filename = get_config_filename(config_name);
+    let text = fs::read_to_string(&config_filename)
+        .map_err(|e| format!("can't open {}\nreason: {}", config_filename, e))?;
+    let text = ConfigFormat::of(&config_filename)
+        .replace_passwords(&text, multi_account, &changed)
+        .map_err(|e| format!("can't update {}\nreason: {}", config_filename, e))?;
+    write_config_text(config_name, &text)?;
+
+    Ok(changed.len())
+}
+
+pub fn load_accounts_file(config_name: &String) -> Result<Vec<AccountConfig>, String> {
+    let (config, unknown) = load_multi_account_file(config_name)?;
+    for key in unknown {
+        warn!(
+            "unknown key {} in {}, ignored",
+            key,
+            get_config_filename(config_name)
+        );
+    }
+
+    Ok(config.accounts)
+}
+
+fn load_multi_account_file(
+    config_name: &String,
+) -> Result<(MultiAccountConfig, Vec<String>), String> {
+    let config_filename = get_config_filename(config_name);
+    let mut unknown = vec![];
+    let raw: RawMultiAccountConfig = read_config_file(&config_filename, &mut unknown)
+        .map_err(|e| format!("{}\nexpect a multi-account config", e))?;
+
+    let mut accounts = vec![];
+    for (i, mut account) in raw.accounts.into_iter().enumerate() {
+        let path = format!("accounts[{}]", i);
+        let name = match account.remove("name") {
+            Some(Value::String(v)) => v,
+            _ => {
+                return Err(format!(
+                    "can't parse {}\nreason: {}.name: expect the account name",
+                    &config_filename, path
+                ))
+            }
+        };
+        let config = deserialize_tracked(Value::Object(account), &path, &mut unknown)
+            .map_err(|e| format!("can't parse {}\nreason: {}", &config_filename, e))?;
+        accounts.push(AccountConfig { name, config });
+    }
+    let config = MultiAccountConfig { accounts };
+
+    let mut names: Vec<&str> = vec![];
+    for (i, account) in config.accounts.iter().enumerate() {
+        if !is_valid_name(&account.name) {
+            return Err(format!(
+                "invalid account name {:?} in {}, use letters, digits, - and _ only",
+                account.name, &config_filename
+            ));
+        }
+        // team.yaml would be taken for a config file, sharing its state, lock and socket
+        if CONFIG_EXTENSIONS.contains(&account.name.to_ascii_lowercase().as_str()) {
+            return Err(format!(
+                "reserved account name {} in {}, {} are config file extensions",
+                account.name,
+                &config_filename,
+                CONFIG_EXTENSIONS.join(", ")
+            ));
+        }
+        if names.contains(&account.name.as_str()) {
+            return Err(format!(
+                "duplicated account {} in {}",
+                account.name, &config_filename
+            ));
+        }
+        names.push(&account.name);
+
+        let problems = account.config.validate();
+        if !problems.is_empty() {
+            return Err(format!(
+                "invalid account {} in {}\n{}",
+                account.name,
+                &config_filename,
+                problems
+                    .iter()
+                    .map(|p| format!("accounts[{}].{}", i, p))
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            ));
+        }
+    }
+
+    Ok((config, unknown))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_catch_up_decide() {
+        let shift = Local.with_ymd_and_hms(2023, 9, 23, 9, 0, 0).unwrap();
+        let early = Local.with_ymd_and_hms(2023, 9, 23, 8, 59, 30).unwrap();
+        let late = Local.with_ymd_and_hms(2023, 9, 23, 9, 10, 0).unwrap();
+
+        let within_0 = CatchUpPolicy::PunchWithin { minutes: 0 };
+        let within_15 = CatchUpPolicy::PunchWithin { minutes: 15 };
+
+        assert_eq!(within_0.decide(&shift, &early), CatchUpAction::PunchNow);
+        assert_eq!(within_0.decide(&shift, &late), CatchUpAction::Skip);
+        assert_eq!(within_15.decide(&shift, &late), CatchUpAction::PunchNow);
+        assert_eq!(
+            CatchUpPolicy::Skip.decide(&shift, &early),
+            CatchUpAction::Skip
+        );
+        assert_eq!(
+            CatchUpPolicy::Notify.decide(&shift, &early),
+            CatchUpAction::Notify
+        );
+    }
+
+    #[test]
+    fn test_catch_up_config_parse() {
+        let config: ConfigPayload = serde_json::from_str(
+            r#"{
+                "username": "u",
+                "password": "p",
+                "company": "c",
+                "catch_up": {
+                    "punch_in": {"policy": "punch_within", "minutes": 30},
+                    "punch_out": {"policy": "notify"}
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.catch_up.punch_in,
+            CatchUpPolicy::PunchWithin { minutes: 30 }
+        );
+        assert_eq!(config.catch_up.punch_out, CatchUpPolicy::Notify);
+
+        assert!(config.planning.time.is_none());
+        assert!(config.presence.is_none());
+
+        let legacy: ConfigPayload =
+            serde_json::from_str(r#"{"username": "u", "password": "p", "company": "c"}"#).unwrap();
+        assert_eq!(legacy.catch_up.punch_in, CatchUpPolicy::default());
+        assert_eq!(legacy.planning.minutes_before_shift, 120);
+        assert_eq!(legacy.password, PasswordSource::Plain("p".to_string()));
+
+        let config: ConfigPayload = serde_json::from_str(
+            r#"{"username": "u", "password": {"command": "pass show mayo"}, "company": "c"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.password,
+            PasswordSource::Command {
+                command: "pass show mayo".to_string()
+            }
+        );
+        assert!(serde_json::from_str::<ConfigPayload>(
+            r#"{"username": "u", "password": {"vault": "mayo"}, "company": "c"}"#,
+        )
+        .unwrap_err()
+        .to_string()
+        .contains(r#"{"env": ...}"#));
+    }
+
+    #[test]
+    fn test_presence_config_parse() {
+        let presence: PresenceConfig = serde_json::from_str(
+            r#"{"check": "subnet", "cidr": "192.168.1.0/24", "wait_minutes": 30}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            presence.check,
+            PresenceCheck::Subnet {
+                cidr: "192.168.1.0/24".to_string()
+            }
+        );
+        assert_eq!(presence.wait_minutes, 30);
+        assert_eq!(presence.interval_seconds, 60);
+    }
+
+    #[test]
+    fn test_accounts_file() {
+        let config_name = std::env::temp_dir()
+            .join(format!("apollo-team-test-{}", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let write = |content: &str| {
+            std::fs::write(get_config_filename(&config_name), content).unwrap();
+        };
+
+        write(
+            r#"{"accounts": [
+                {"name": "alice", "username": "1", "password": "p", "company": "c"},
+                {"name": "bob", "username": "2", "password": "p", "company": "c",
+                 "catch_up": {"punch_in": {"policy": "skip"}}}
+            ]}"#,
+        );
+        let accounts = load_accounts_file(&config_name).unwrap();
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[1].config.catch_up.punch_in, CatchUpPolicy::Skip);
+
+        let bob = ConfigSource::Account {
+            config_name: config_name.clone(),
+            account: "bob".to_string(),
+        };
+        assert_eq!(bob.name(), format!("{}.bob", config_name));
+        assert_eq!(
+            get_socket_filename(&bob.name()),
+            format!("{}.bob.sock", config_name)
+        );
+        assert_eq!(bob.load().unwrap().username, "2");
+        assert!(ConfigSource::Account {
+            config_name: config_name.clone(),
+            account: "carol".to_string(),
+        }
+        .load()
+        .is_err());
+
+        write(
+            r#"{"accounts": [
+                {"name": "alice", "username": "1", "password": "p", "company": "c"},
+                {"name": "alice", "username": "2", "password": "p", "company": "c"}
+            ]}"#,
+        );
+        assert!(load_accounts_file(&config_name)
+            .unwrap_err()
+            .contains("duplicated account alice"));
+
+        write(
+            r#"{"accounts": [{"name": "../x", "username": "1", "password": "p", "company": "c"}]}"#,
+        );
+        assert!(load_accounts_file(&config_name)
+            .unwrap_err()
+            .contains("invalid account name"));
+
+        write(
+            r#"{"accounts": [{"name": "yaml", "username": "1", "password": "p", "company": "c"}]}"#,
+        );
+        assert!(load_accounts_file(&config_name)
+            .unwrap_err()
+            .contains("reserved account name yaml"));
+
+        std::fs::remove_file(get_config_filename(&config_name)).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_config_name() {
+        let root = std::env::temp_dir().join(format!("apollo-config-test-{}", std::process::id()));
+        let cwd = root.join("cwd");
+        let config_dir = root.join("apollo");
+        fs::create_dir_all(&cwd).unwrap();
+        let resolve = |config, profile, env_config| {
+            resolve_config_name(config, profile, env_config, &cwd, Some(&config_dir))
+        };
+        let in_dir = |filename: &str| config_dir.join(filename).to_string_lossy().to_string();
+
+        assert_eq!(resolve(None, None, None), Ok(in_dir("config")));
+        assert_eq!(
+            resolve(Some("mine"), Some("work"), Some("env")),
+            Ok("mine".to_string())
+        );
+        assert_eq!(resolve(None, Some("work"), Some("env")), Ok(in_dir("work")));
+        assert!(resolve(None, Some("../work"), None).is_err());
+        assert_eq!(resolve(None, None, Some("env")), Ok("env".to_string()));
+        assert!(resolve_config_name(None, Some("work"), None, &cwd, None).is_err());
+
+        fs::write(cwd.join("config.toml"), "").unwrap();
+        assert_eq!(resolve(None, None, None), Ok("config".to_string()));
+
+        assert_eq!(list_profiles(&config_dir), Ok(vec![]));
+        // creates the config directory
+        write_config_file(
+            &in_dir("work"),
+            &serde_json::from_str::<ConfigPayload>(
+                r#"{"username": "u", "password": "p", "company": "c"}"#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert!(!Path::new(&in_dir("work.json.tmp")).exists());
+        // a failure is returned, not a panic
+        assert!(write_config_file(&in_dir("work.json/nested"), &Value::Null).is_err());
+        assert_eq!(
+            fs::metadata(in_dir("work.json"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777,
+            0o600
+        );
+        for name in ["work.state.json", "home.yaml", "work.lock"] {
+            fs::write(config_dir.join(name), "{}").unwrap();
+        }
+        assert_eq!(
+            list_profiles(&config_dir),
+            Ok(vec![
+                ("home".to_string(), in_dir("home.yaml")),
+                ("work".to_string(), in_dir("work.json")),
+            ])
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_config_formats() {
+        let dir = std::env::temp_dir().join(format!("apollo-format-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config_name = dir.join("config").to_string_lossy().to_string();
+        let write = |filename: &str, content: &str| {
+            for ext in CONFIG_EXTENSIONS {
+                let _ = fs::remove_file(dir.join(format!("config.{}", ext)));
+            }
+            fs::write(dir.join(filename), content).unwrap();
+        };
+
+        write(
+            "config.toml",
+            r#"
+username = "u"
+company = "c"
+planing = "typo"
+
+[password]
+env = "MAYO_PASSWORD"
+
+[punch]
+jitter_seconds = 0
+
+[catch_up.punch_in]
+policy = "skip"
+"#,
+        );
+        let config = load_config_file(&config_name).unwrap();
+        assert_eq!(
+            config.password,
+            PasswordSource::Env {
+                env: "MAYO_PASSWORD".to_string()
+            }
+        );
+        assert_eq!(config.punch.jitter_seconds, 0);
+        assert_eq!(config.catch_up.punch_in, CatchUpPolicy::Skip);
+        assert_eq!(config.mayo, MayoConfig::default());
+        assert_eq!(
+            check_config_file(&config_name),
+            Ok(vec!["planing".to_string()])
+        );
+        assert_eq!(
+            get_state_filename(&config_name),
+            format!("{}.state.json", config_name)
+        );
+
+        // written back in the same format
+        write_config_file(&config_name, &config).unwrap();
+        assert!(fs::read_to_string(dir.join("config.toml"))
+            .unwrap()
+            .contains("[password]"));
+        assert_eq!(check_config_file(&config_name), Ok(vec![]));
+
+        write(
+            "config.yaml",
+            "username: u\npassword: p\ncompany: c\nmqtt:\n  host: broker\n  port: mqtt\n",
+        );
+        assert!(load_config_file(&config_name)
+            .unwrap_err()
+            .contains("mqtt.port: invalid type"));
+
+        write(
+            "config.json",
+            r#"{"username": "u", "password": "p", "company": "c",
+                "presence": {"check": "subnet", "cidr": "192.168.1.0/33"},
+                "notifications": {"webhooks": [{"url": "not a url"}]}}"#,
+        );
+        let e = load_config_file(&config_name).unwrap_err();
+        assert!(e.contains("presence.cidr: invalid subnet"));
+        assert!(e.contains("notifications.webhooks[0].url: must be an http(s) URL"));
+
+        // accounts of a multi-account config are checked like single-account configs
+        write(
+            "config.json",
+            r#"{"accounts": [
+                {"name": "a", "username": "a", "password": "p", "company": "c"},
+                {"name": "b", "username": "b", "password": "p", "company": "c", "planing": {}}
+            ]}"#,
+        );
+        assert_eq!(
+            check_config_file(&config_name),
+            Ok(vec!["accounts[1].planing".to_string()])
+        );
+        write(
+            "config.yaml",
+            "accounts:\n- name: a\n  username: a\n  password: p\n  company: c\n  mqtt:\n    host: broker\n    port: bad\n",
+        );
+        assert!(load_accounts_file(&config_name)
+            .unwrap_err()
+            .contains("accounts[0].mqtt.port: invalid type"));
+        write(
+            "config.toml",
+            "[[accounts]]\nname = \"a\"\nusername = \"a\"\npassword = \"p\"\ncompany = \"c\"\n\n[accounts.punch]\njitter_seconds = -1\n",
+        );
+        assert!(check_config_file(&config_name)
+            .unwrap_err()
+            .contains("accounts[0].punch.jitter_seconds: invalid value"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_update_passwords() {
+        let dir = std::env::temp_dir().join(format!("apollo-update-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config_name = dir.join("config").to_string_lossy().to_string();
+        let encrypt = |password: &PasswordSource| {
+            Ok(match password {
+                PasswordSource::Plain(v) => Some(PasswordSource::Encrypted {
+                    encrypted: format!("sealed {}", v),
+                }),
+                _ => None,
+            })
+        };
+
+        fs::write(
+            dir.join("config.toml"),
+            r#"# my accounts
+planing = "typo"
+
+[[accounts]]
+name = "a"
+username = "a"
+password = "pa" # to be encrypted
+company = "c"
+
+[[accounts]]
+name = "b"
+username = "b"
+password = { env = "PW" }
+company = "c"
+"#,
+        )
+        .unwrap();
+        assert_eq!(update_passwords(&config_name, encrypt), Ok(1));
+
+        let text = fs::read_to_string(dir.join("config.toml")).unwrap();
+        // only the changed password is touched, comments and unknown keys stay
+        assert!(text.starts_with("# my accounts\nplaning = \"typo\"\n"));
+        assert!(text.contains("password = { encrypted = \"sealed pa\" } # to be encrypted\n"));
+        assert!(text.contains("password = { env = \"PW\" }\n"));
+        assert!(!text.contains("catch_up"));
+        fs::remove_file(dir.join("config.toml")).unwrap();
+
+        fs::write(
+            dir.join("config.json"),
+            r#"{"username": "u", "password": "p", "company": "c", "extra": 1}"#,
+        )
+        .unwrap();
+        assert_eq!(update_passwords(&config_name, encrypt), Ok(1));
+        let config: Value =
+            serde_json::from_str(&fs::read_to_string(dir.join("config.json")).unwrap()).unwrap();
+        assert_eq!(
+            config,
+            serde_json::json!({"username": "u", "password": {"encrypted": "sealed p"},
+                "company": "c", "extra": 1})
+        );
+        fs::remove_file(dir.join("config.json")).unwrap();
+
+        fs::write(
+            dir.join("config.yaml"),
+            r#"# team
+accounts:
+  - name: a
+    username: a
+    password: pa # to be encrypted
+    company: c
+
+  - name: b
+    username: b
+    password:
+      env: PW
+    company: c
+"#,
+        )
+        .unwrap();
+        assert_eq!(update_passwords(&config_name, encrypt), Ok(1));
+        assert_eq!(
+            fs::read_to_string(dir.join("config.yaml")).unwrap(),
+            r#"# team
+accounts:
+  - name: a
+    username: a
+    password: # to be encrypted
+      encrypted: sealed pa
+    company: c
+
+  - name: b
+    username: b
+    password:
+      env: PW
+    company: c
+"#
+        );
+        fs::remove_file(dir.join("config.yaml")).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_yaml_replace_password() {
+        let encrypted = serde_yaml::to_value(PasswordSource::Encrypted {
+            encrypted: "-----BEGIN\nsealed\n-----END\n".to_string(),
+        })
+        .unwrap();
+        let text = "username: u\npassword: \"p # not a comment\"\ncompany: c # ACME\n";
+        let replaced = yaml_replace_password(text, None, &encrypted).unwrap();
+        assert_eq!(
+            replaced,
+            "username: u\npassword:\n  encrypted: |\n    -----BEGIN\n    sealed\n    -----END\ncompany: c # ACME\n"
+        );
+        // and back, the block scalar goes with it
+        let plain = serde_yaml::to_value(PasswordSource::Plain("p".to_string())).unwrap();
+        assert_eq!(
+            yaml_replace_password(&replaced, None, &plain).unwrap(),
+            "username: u\npassword: p\ncompany: c # ACME\n"
+        );
+
+        // items at the indent of the list key, and keys starting below a lone -
+        let text =
+            "accounts:\n- name: a\n  password: pa\n-\n  name: b\n  password: pb\nlogging: {}\n";
+        assert_eq!(
+            yaml_replace_password(text, Some(1), &plain).unwrap(),
+            "accounts:\n- name: a\n  password: pa\n-\n  name: b\n  password: p\nlogging: {}\n"
+        );
+        assert_eq!(yaml_replace_password(text, Some(2), &plain), None);
+        assert_eq!(
+            yaml_replace_password("accounts: [{password: pa}]\n", Some(0), &plain),
+            None
+        );
+    }
+
+    #[test]
+    fn test_planning_time() {
+        let work_day = WorkdaySchedule::from_json(&serde_json::json!({
+            "Date": "2023-09-23T00:00:00+00:00",
+            "ShiftSchedule": {
+                "WorkOnTime": "2023-09-22T22:00:00+00:00",
+                "WorkOffTime": "2023-09-23T07:00:00+00:00"
+            }
+        }));
+        let holiday = WorkdaySchedule::from_json(&serde_json::json!({
+            "Date": "2023-09-24T00:00:00+00:00",
+            "ShiftSchedule": {"WorkOnTime": null, "WorkOffTime": null}
+        }));
+
+        let planning: PlanningConfig =
+            serde_json::from_str(r#"{"minutes_before_shift": 90}"#).unwrap();
+        assert_eq!(
+            planning.get_planning_time(&work_day),
+            Local.with_ymd_and_hms(2023, 9, 23, 4, 30, 0).unwrap()
+        );
+        assert_eq!(
+            planning.get_planning_time(&holiday),
+            Local.with_ymd_and_hms(2023, 9, 24, 7, 0, 0).unwrap()
+        );
+
+        let planning: PlanningConfig = serde_json::from_str(r#"{"time": "05:15"}"#).unwrap();
+        assert_eq!(
+            planning.get_planning_time(&work_day),
+            Local.with_ymd_and_hms(2023, 9, 23, 5, 15, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_wake_up_time_on_previous_day() {
+        // shift at 01:00, planned at 23:00 the evening before
+        let night_shift = WorkdaySchedule::from_json(&serde_json::json!({
+            "Date": "2023-09-24T00:00:00+00:00",
+            "ShiftSchedule": {
+                "WorkOnTime": "2023-09-23T17:00:00+00:00",
+                "WorkOffTime": "2023-09-24T02:00:00+00:00"
+            }
+        }));
+        let planning = PlanningConfig::default();
+        let evening = Local.with_ymd_and_hms(2023, 9, 23, 23, 0, 0).unwrap();
+        assert_eq!(planning.get_planning_time(&night_shift), evening);
+
+        let before = evening - Duration::hours(5);
+        assert_eq!(
+            planning.get_wake_up_time(&night_shift, &before),
+            Some(evening)
+        );
+        // already passed, never a target to wait for
+        assert_eq!(planning.get_wake_up_time(&night_shift, &evening), None);
+        assert_eq!(
+            planning.get_wake_up_time(&night_shift, &(evening + Duration::minutes(30))),
+            None
+        );
+    }
+}
@@ -0,0 +1,308 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::Duration as StdDuration;
+
+use chrono::{Duration, Local};
+use serde_json::Value;
+use tracing::warn;
+
+use super::agent::PunchType;
+use super::daemon::DaemonEvent;
+use super::lock::InstanceLock;
+use super::plan::PunchStatus;
+
+/// How long a client waits for the daemon, which may be in the middle of a punch request.
+const REPLY_TIMEOUT: StdDuration = StdDuration::from_secs(60);
+
+/// Commands accepted on the control socket, one command per line.
+#[derive(Debug, PartialEq)]
+pub enum ControlCommand {
+    /// `status`
+    Status,
+    /// `skip today`
+    SkipToday,
+    /// `delay punch-in|punch-out <duration>`, e.g. `delay punch-out 90m`
+    Delay(PunchType, Duration),
+    /// `punch-now in|out`
+    PunchNow(PunchType),
+    /// `replan`
+    Replan,
+}
+
+/// Parses durations like `90m`, `1h30m` or `45s`.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let mut total = Duration::zero();
+    let mut number = String::new();
+
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let value: i64 = number
+            .parse()
+            .map_err(|_| format!("invalid duration {}", s))?;
+        number.clear();
+
+        total += match c {
+            'h' => Duration::hours(value),
+            'm' => Duration::minutes(value),
+            's' => Duration::seconds(value),
+            _ => return Err(format!("invalid duration unit {} in {}", c, s)),
+        };
+    }
+
+    if !number.is_empty() || total.is_zero() {
+        return Err(format!(
+            "invalid duration {}, expect something like 90m or 1h30m",
+            s
+        ));
+    }
+
+    Ok(total)
+}
+
+fn parse_punch_type(s: &str) -> Result<PunchType, String> {
+    match s {
+        "in" | "punch-in" => Ok(PunchType::PunchIn),
+        "out" | "punch-out" => Ok(PunchType::PunchOut),
+        _ => Err(format!("unknown punch {}, expect in or out", s)),
+    }
+}
+
+impl FromStr for ControlCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+
+        match words.as_slice() {
+            ["status"] => Ok(ControlCommand::Status),
+            ["skip", "today"] => Ok(ControlCommand::SkipToday),
+            ["delay", punch, duration] => Ok(ControlCommand::Delay(
+                parse_punch_type(punch)?,
+                parse_duration(duration)?,
+            )),
+            ["punch-now", punch] => Ok(ControlCommand::PunchNow(parse_punch_type(punch)?)),
+            ["replan"] => Ok(ControlCommand::Replan),
+            _ => Err(format!("unknown command: {}", s.trim())),
+        }
+    }
+}
+
+/// Listens on the daemon's control socket, removing the socket file when dropped.
+pub struct ControlServer {
+    path: String,
+}
+
+impl ControlServer {
+    /// Binds `path` and forwards every received command into `sender` as `DaemonEvent::Control`.
+    pub fn spawn(path: &str, sender: Sender<DaemonEvent>) -> Result<Self, String> {
+        // the instance lock is held, so an existing socket is a leftover of a killed daemon
+        let _ = fs::remove_file(path);
+
+        let listener = UnixListener::bind(path)
+            .map_err(|e| format!("can't listen on control socket {}\nreason: {}", path, e))?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600)).map_err(|e| {
+            format!(
+                "can't set permissions of control socket {}\nreason: {}",
+                path, e
+            )
+        })?;
+
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Err(e) = serve(stream, &sender) {
+                    warn!("control socket: {}", e);
+                }
+            }
+        });
+
+        Ok(ControlServer {
+            path: path.to_string(),
+        })
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn serve(mut stream: UnixStream, sender: &Sender<DaemonEvent>) -> Result<(), String> {
+    let mut line = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut line)
+        .map_err(|e| e.to_string())?;
+
+    let reply = match line.parse::<ControlCommand>() {
+        Ok(command) => {
+            let (reply_sender, reply_receiver) = channel();
+            sender
+                .send(DaemonEvent::Control(command, reply_sender))
+                .map_err(|e| e.to_string())?;
+            reply_receiver
+                .recv_timeout(REPLY_TIMEOUT)
+                .unwrap_or_else(|e| format!("error: no reply from daemon, {}", e))
+        }
+        Err(e) => format!("error: {}", e),
+    };
+
+    stream
+        .write_all(reply.as_bytes())
+        .map_err(|e| e.to_string())
+}
+
+/// Sends one command to the daemon listening on `path` and returns its reply.
+pub fn send_command(path: &str, command: &str) -> Result<String, String> {
+    let mut stream = UnixStream::connect(path).map_err(|e| {
+        format!(
+            "can't connect to control socket {}, is auto-punch running?\nreason: {}",
+            path, e
+        )
+    })?;
+
+    stream
+        .write_all(format!("{}\n", command).as_bytes())
+        .map_err(|e| e.to_string())?;
+
+    let mut reply = String::new();
+    stream
+        .read_to_string(&mut reply)
+        .map_err(|e| e.to_string())?;
+
+    Ok(reply)
+}
+
+/// Turns the daemon's reply to `punch-now` back into the status of the punch.
+fn parse_punch_reply(punch_type: PunchType, reply: &str) -> PunchStatus {
+    let (head, detail) = reply.split_once('\n').unwrap_or((reply, ""));
+    let at = Local::now();
+
+    if head == format!("{} done", punch_type) {
+        PunchStatus::Done {
+            at,
+            response: serde_json::from_str(detail)
+                .unwrap_or_else(|_| Value::String(detail.to_string())),
+        }
+    } else if head == format!("error: {} skipped", punch_type) {
+        PunchStatus::Skipped {
+            at,
+            reason: detail.to_string(),
+        }
+    } else if head == format!("error: {} failed", punch_type) {
+        PunchStatus::Failed {
+            at,
+            error: detail.to_string(),
+        }
+    } else {
+        PunchStatus::Failed {
+            at,
+            error: reply.to_string(),
+        }
+    }
+}
+
+/// Punches through the daemon holding `lock_path`, so the punch is marked done in its plan
+/// instead of being punched again later, returns None when no daemon is running.
+pub fn punch_via_daemon(
+    lock_path: &str,
+    socket_path: &str,
+    punch_type: PunchType,
+) -> Result<Option<PunchStatus>, String> {
+    if InstanceLock::holder(lock_path)?.is_none() {
+        return Ok(None);
+    }
+
+    let command = match punch_type {
+        PunchType::PunchIn => "punch-now in",
+        PunchType::PunchOut => "punch-now out",
+    };
+    let reply = send_command(socket_path, command)?;
+    Ok(Some(parse_punch_reply(punch_type, &reply)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90m"), Ok(Duration::minutes(90)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::minutes(90)));
+        assert_eq!(parse_duration("45s"), Ok(Duration::seconds(45)));
+        assert!(parse_duration("90").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("1d").is_err());
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!("status\n".parse(), Ok(ControlCommand::Status));
+        assert_eq!("skip today".parse(), Ok(ControlCommand::SkipToday));
+        assert_eq!(
+            "delay punch-out 90m".parse(),
+            Ok(ControlCommand::Delay(
+                PunchType::PunchOut,
+                Duration::minutes(90)
+            ))
+        );
+        assert_eq!(
+            "punch-now in".parse(),
+            Ok(ControlCommand::PunchNow(PunchType::PunchIn))
+        );
+        assert_eq!("replan".parse(), Ok(ControlCommand::Replan));
+        assert!("skip tomorrow".parse::<ControlCommand>().is_err());
+    }
+
+    #[test]
+    fn test_parse_punch_reply() {
+        let status = parse_punch_reply(PunchType::PunchIn, "PunchIn done\n{\"Data\":1}");
+        assert!(
+            matches!(status, PunchStatus::Done { response, .. } if response == serde_json::json!({"Data": 1}))
+        );
+
+        let status = parse_punch_reply(PunchType::PunchOut, "error: PunchOut skipped\nvetoed");
+        assert!(matches!(status, PunchStatus::Skipped { reason, .. } if reason == "vetoed"));
+
+        let status = parse_punch_reply(PunchType::PunchOut, "error: PunchOut failed\ntimeout");
+        assert!(matches!(status, PunchStatus::Failed { error, .. } if error == "timeout"));
+
+        let status = parse_punch_reply(PunchType::PunchIn, "error: no reply from daemon");
+        assert!(
+            matches!(status, PunchStatus::Failed { error, .. } if error == "error: no reply from daemon")
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let path = std::env::temp_dir()
+            .join(format!("apollo-control-test-{}.sock", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+
+        let (sender, events) = channel();
+        let server = ControlServer::spawn(&path, sender).unwrap();
+
+        thread::spawn(move || {
+            if let Ok(DaemonEvent::Control(ControlCommand::Status, reply)) = events.recv() {
+                reply.send("all good".to_string()).unwrap();
+            }
+        });
+
+        assert_eq!(send_command(&path, "status").unwrap(), "all good");
+        assert!(send_command(&path, "dance")
+            .unwrap()
+            .starts_with("error: unknown command"));
+
+        drop(server);
+        assert!(send_command(&path, "status").is_err());
+    }
+}
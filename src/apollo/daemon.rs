@@ -0,0 +1,688 @@
+use std::process;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+use chrono::{DateTime, Duration, Local, NaiveDate};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use tracing::{error, info, info_span, warn};
+
+use super::agent::{ApolloAgent, PunchType};
+use super::config::{
+    get_socket_filename, get_state_filename, CatchUpAction, ConfigPayload, ConfigSource,
+    HooksConfig,
+};
+use super::control::{ControlCommand, ControlServer};
+use super::hooks::punch_with_hooks;
+use super::lock::InstanceLock;
+use super::metrics;
+use super::mqtt::MqttBridge;
+use super::notify::{Notification, Notifier};
+use super::plan::{DayPlan, PlannedPunch, PunchStatus};
+use super::presence::check_presence;
+use super::utils::sleep_until_interruptible;
+use super::workday_schedule::WorkdaySchedule;
+
+/// Wait before `run_retrying` starts over after a failure.
+const RETRY_DELAY_MINUTES: i64 = 5;
+
+pub enum DaemonEvent {
+    /// stop the daemon, carries the name of the received signal
+    Shutdown(&'static str),
+    /// reload the config file and re-plan the current day
+    Reload,
+    /// command received on the control socket, the reply goes into the sender
+    Control(ControlCommand, Sender<String>),
+}
+
+/// What the daemon does after handling an event.
+enum Action {
+    /// keep going with the current plan
+    Continue,
+    /// re-login, re-fetch the schedule and re-plan the current day
+    Replan,
+    Shutdown,
+}
+
+enum DayOutcome {
+    Finished,
+    Replan,
+    Shutdown,
+}
+
+pub struct Daemon {
+    source: ConfigSource,
+    config: ConfigPayload,
+    agent: ApolloAgent,
+    events: Receiver<DaemonEvent>,
+    notifier: Notifier,
+
+    state_filename: String,
+    /// plan of the current day, persisted into `state_filename` on every change
+    plan: Option<DayPlan>,
+    /// day skipped by `ctl skip today` before its plan was made
+    skip_date: Option<NaiveDate>,
+    /// what the daemon is waiting for, reported by `ctl status`
+    next_action: String,
+    /// error of the last failed login, notified once till a login succeeds or it changes
+    login_error: Option<String>,
+
+    _lock: InstanceLock,
+    _control: ControlServer,
+    mqtt: Option<MqttBridge>,
+}
+
+/// Forwards SIGTERM/SIGINT as `Shutdown` and SIGHUP as `Reload` into `sender`.
+pub fn spawn_signal_listener(sender: Sender<DaemonEvent>) -> Result<(), String> {
+    let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP]).map_err(|e| e.to_string())?;
+
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            let event = match signal {
+                SIGHUP => DaemonEvent::Reload,
+                SIGINT => DaemonEvent::Shutdown("SIGINT"),
+                _ => DaemonEvent::Shutdown("SIGTERM"),
+            };
+            if sender.send(event).is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(())
+}
+
+impl Daemon {
+    pub fn new(
+        source: ConfigSource,
+        config: ConfigPayload,
+        agent: ApolloAgent,
+        lock: InstanceLock,
+    ) -> Result<Self, String> {
+        let (sender, events) = channel();
+        spawn_signal_listener(sender.clone())?;
+        let control = ControlServer::spawn(&get_socket_filename(&source.name()), sender.clone())?;
+        let mqtt = match config.mqtt {
+            Some(_) => Some(MqttBridge::spawn(&config, sender)?),
+            None => None,
+        };
+
+        let state_filename = get_state_filename(&source.name());
+        let plan = DayPlan::load(&state_filename).unwrap_or_else(|e| {
+            warn!("{}", e);
+            warn!("start with an empty plan");
+            None
+        });
+
+        Ok(Daemon {
+            source,
+            notifier: Notifier::from_config(&config)?,
+            config,
+            agent,
+            events,
+            state_filename,
+            plan,
+            skip_date: None,
+            next_action: "plan the day".to_string(),
+            login_error: None,
+            _lock: lock,
+            _control: control,
+            mqtt,
+        })
+    }
+
+    pub fn run(&mut self) -> Result<(), String> {
+        let today = Local::now().date_naive();
+        // the day being planned, a plan resumed from the state file may already be for tomorrow
+        let mut date = self.plan.as_ref().map_or(today, |p| p.date.max(today));
+        // the schedule fetched when the current wake up time was planned
+        let mut cached_schedule: Option<WorkdaySchedule> = None;
+
+        loop {
+            // always re-login
+            self.login()?;
+
+            // the wake up time may be on the evening before, so not today's schedule
+            let schedule = self.agent.get_workday_schedule(date)?;
+
+            if let Some(cached) = cached_schedule.take() {
+                if cached != schedule {
+                    info!(
+                        "schedule changed since last planning, was: {}, now: {}",
+                        cached, schedule
+                    );
+                    self.notifier.notify(Notification::ScheduleAnomaly {
+                        date,
+                        detail: format!(
+                            "changed since last planning, was: {}, now: {}",
+                            cached, schedule
+                        ),
+                    });
+
+                    let now = Local::now();
+                    if let Some(replanned_time) =
+                        self.config.planning.get_wake_up_time(&schedule, &now)
+                    {
+                        info!("re-planned wake up time: {}", replanned_time);
+                        cached_schedule = Some(schedule);
+                        match self.wait_for_planning(&replanned_time) {
+                            Action::Continue => {}
+                            Action::Replan => cached_schedule = None,
+                            Action::Shutdown => return Ok(()),
+                        }
+                        continue;
+                    }
+                }
+            }
+
+            match self.run_day(&schedule) {
+                DayOutcome::Finished => {}
+                // plan the same day again
+                DayOutcome::Replan => continue,
+                DayOutcome::Shutdown => return Ok(()),
+            }
+
+            // the session may have expired during the day
+            self.login()?;
+
+            date = date.succ_opt().unwrap();
+            let next_schedule = self.agent.get_workday_schedule(date)?;
+            let planning_time = match self
+                .config
+                .planning
+                .get_wake_up_time(&next_schedule, &Local::now())
+            {
+                Some(v) => v,
+                None => {
+                    info!("wake up time of {} has passed, plan it right away", date);
+                    continue;
+                }
+            };
+
+            info!("next wake up time: {}", planning_time);
+            cached_schedule = Some(next_schedule);
+            match self.wait_for_planning(&planning_time) {
+                Action::Continue => {}
+                Action::Replan => cached_schedule = None,
+                Action::Shutdown => return Ok(()),
+            }
+        }
+    }
+
+    /// Like `run`, but starts over after a failure instead of returning it,
+    /// for accounts sharing one process.
+    pub fn run_retrying(&mut self) {
+        loop {
+            let e = match self.run() {
+                Ok(()) => return,
+                Err(e) => e,
+            };
+
+            let retry_time = Local::now() + Duration::minutes(RETRY_DELAY_MINUTES);
+            error!("{}", e);
+            error!("starting over at {}", retry_time);
+            self.next_action = format!("start over at {}", retry_time);
+            if let Action::Shutdown = self.sleep_through(&retry_time) {
+                return;
+            }
+        }
+    }
+
+    /// Logs in, a failure is notified unless it is the same as the last one,
+    /// `run_retrying` would repeat it every few minutes.
+    fn login(&mut self) -> Result<(), String> {
+        match self.agent.login() {
+            Ok(()) => {
+                self.login_error = None;
+                Ok(())
+            }
+            Err(e) => {
+                if self.login_error.as_ref() != Some(&e) {
+                    self.notifier.notify(Notification::LoginFailed {
+                        at: Local::now(),
+                        error: e.clone(),
+                    });
+                    self.login_error = Some(e.clone());
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Sleeps till `target` while handling events, returns None when `target` is reached,
+    /// or the action of the event which interrupted the sleep.
+    fn wait_until(&mut self, target: &DateTime<Local>) -> Option<Action> {
+        sleep_until_interruptible(target, &self.events).map(|event| self.handle_event(event))
+    }
+
+    /// Sleeps till the next planning time, only interrupted by re-plans and shutdowns.
+    fn wait_for_planning(&mut self, target: &DateTime<Local>) -> Action {
+        self.next_action = format!("plan the day at {}", target);
+        self.sleep_through(target)
+    }
+
+    /// Sleeps till `target`, only interrupted by re-plans and shutdowns.
+    fn sleep_through(&mut self, target: &DateTime<Local>) -> Action {
+        loop {
+            match self.wait_until(target) {
+                None => return Action::Continue,
+                Some(Action::Continue) => {}
+                Some(action) => return action,
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: DaemonEvent) -> Action {
+        match event {
+            DaemonEvent::Shutdown(signal) => {
+                info!("received {}, shutting down", signal);
+                if let Some(plan) = &self.plan {
+                    for p in plan.punches.iter().filter(|p| p.is_pending()) {
+                        warn!(
+                            "planned but not executed: {} at {}",
+                            p.punch_type, p.planned_time
+                        );
+                    }
+                }
+                Action::Shutdown
+            }
+            DaemonEvent::Reload => {
+                self.reload();
+                Action::Replan
+            }
+            DaemonEvent::Control(command, reply) => {
+                info!("control command: {:?}", command);
+                let (text, action) = self.handle_control(command);
+                let _ = reply.send(text);
+                action
+            }
+        }
+    }
+
+    /// The plan being executed, if it still has pending punches, it may be for tomorrow
+    /// when the wake up time is on the evening before or a night shift runs past midnight.
+    fn current_plan(&mut self) -> Option<&mut DayPlan> {
+        self.plan.as_mut().filter(|p| p.next_pending().is_some())
+    }
+
+    fn handle_control(&mut self, command: ControlCommand) -> (String, Action) {
+        match command {
+            ControlCommand::Status => (self.status(), Action::Continue),
+            ControlCommand::SkipToday => {
+                let date = match self.current_plan() {
+                    Some(plan) => {
+                        plan.skip_pending("skipped by ctl");
+                        plan.date
+                    }
+                    None => {
+                        let today = Local::now().date_naive();
+                        self.skip_date = Some(today);
+                        today
+                    }
+                };
+                self.save_plan();
+                (
+                    format!("pending punches of {} skipped", date),
+                    Action::Continue,
+                )
+            }
+            ControlCommand::Delay(punch_type, duration) => {
+                let reply = match self.current_plan() {
+                    Some(plan) => match plan.delay(punch_type, duration) {
+                        Ok(p) => format!("{} delayed to {}", punch_type, p.planned_time),
+                        Err(e) => format!("error: {}", e),
+                    },
+                    None => "error: no pending punches planned".to_string(),
+                };
+                self.save_plan();
+                (reply, Action::Continue)
+            }
+            ControlCommand::PunchNow(punch_type) => {
+                let scheduled_time = self.current_plan().and_then(|plan| {
+                    plan.punches
+                        .iter()
+                        .find(|p| p.punch_type == punch_type && p.is_pending())
+                        .map(|p| p.planned_time)
+                });
+                let status = self.punch(punch_type, scheduled_time);
+                let reply = match &status {
+                    PunchStatus::Done { response, .. } => {
+                        format!("{} done\n{}", punch_type, response)
+                    }
+                    PunchStatus::Failed { error, .. } => {
+                        format!("error: {} failed\n{}", punch_type, error)
+                    }
+                    PunchStatus::Skipped { reason, .. } => {
+                        format!("error: {} skipped\n{}", punch_type, reason)
+                    }
+                    PunchStatus::Pending => unreachable!(),
+                };
+                // the pending punch is done now, the daemon must not punch it again
+                if scheduled_time.is_some() {
+                    if let Some(plan) = self.current_plan() {
+                        plan.set_status(punch_type, status);
+                    }
+                }
+                self.save_plan();
+                (reply, Action::Continue)
+            }
+            ControlCommand::Replan => {
+                if let Some(plan) = self.current_plan() {
+                    plan.drop_pending();
+                }
+                ("re-planning".to_string(), Action::Replan)
+            }
+        }
+    }
+
+    fn status(&self) -> String {
+        format!(
+            "auto punch daemon pid {}{}\n{}\nnext action: {}",
+            process::id(),
+            if self.agent.is_dry_run() {
+                " (dry run)"
+            } else {
+                ""
+            },
+            self.plan
+                .as_ref()
+                .map_or("no plan yet".to_string(), |p| p.to_string()),
+            self.next_action
+        )
+    }
+
+    fn reload(&mut self) {
+        info!("reloading config {}", self.source.name());
+
+        let (config, notifier, password) = match self.source.load().and_then(|config| {
+            let notifier = Notifier::from_config(&config)?;
+            let password = config.resolve_password()?;
+            Ok((config, notifier, password))
+        }) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("reload failed, keep using the current config\n{}", e);
+                return;
+            }
+        };
+
+        self.agent.set_credentials(
+            config.username.as_str(),
+            password.as_str(),
+            config.company.as_str(),
+        );
+        self.agent.set_mayo(&config.mayo);
+        self.notifier = notifier;
+        self.config = config;
+    }
+
+    fn save_plan(&self) {
+        if let Some(plan) = &self.plan {
+            for punch_type in [PunchType::PunchIn, PunchType::PunchOut] {
+                metrics::set_next_punch(
+                    punch_type,
+                    plan.punches
+                        .iter()
+                        .find(|p| p.punch_type == punch_type && p.is_pending())
+                        .map(|p| &p.planned_time),
+                );
+            }
+            if let Some(mqtt) = &self.mqtt {
+                mqtt.publish_plan(plan);
+            }
+
+            if let Err(e) = plan.save(&self.state_filename) {
+                error!("{}", e);
+            }
+        }
+    }
+
+    fn run_day(&mut self, schedule: &WorkdaySchedule) -> DayOutcome {
+        let date = schedule.get_naive_date();
+        let _span = info_span!("day", %date).entered();
+
+        info!("{}", schedule);
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.publish_schedule(schedule);
+        }
+
+        let plan = match self.plan.take() {
+            Some(mut plan) if plan.date == date => {
+                if plan.update(schedule, self.config.punch.jitter_seconds) {
+                    info!("schedule of {} changed, pending punches re-arranged", date);
+                    self.notifier.notify(Notification::ScheduleAnomaly {
+                        date,
+                        detail: format!("changed during the day, now: {}", schedule),
+                    });
+                }
+                plan
+            }
+            _ => DayPlan::new(schedule, self.config.punch.jitter_seconds),
+        };
+        self.plan = Some(plan);
+        if self.skip_date == Some(date) {
+            self.plan.as_mut().unwrap().skip_pending("skipped by ctl");
+        }
+        self.save_plan();
+
+        if !schedule.is_work_day() {
+            info!("{} is not work day", schedule.get_date());
+            return DayOutcome::Finished;
+        }
+
+        if schedule.get_shift_time(PunchType::PunchIn).is_none()
+            || schedule.get_shift_time(PunchType::PunchOut).is_none()
+        {
+            warn!("{} has only one of the shift times", date);
+            self.notifier.notify(Notification::ScheduleAnomaly {
+                date,
+                detail: format!("work day with only one of the shift times: {}", schedule),
+            });
+        }
+
+        let plan = self.plan.as_mut().unwrap();
+        info!("{}", plan);
+        // once a day, not again on re-plans, reloads and restarts
+        if !plan.summary_sent {
+            plan.summary_sent = true;
+            self.notifier
+                .notify(Notification::DayPlanned { plan: plan.clone() });
+            self.save_plan();
+        }
+
+        let plan = self.plan.as_ref().unwrap();
+        for p in plan.missed(&Local::now()) {
+            warn!(
+                "{} planned at {} is still pending, its planned time has passed",
+                p.punch_type, p.planned_time
+            );
+            self.notifier.notify(Notification::ScheduleAnomaly {
+                date,
+                detail: format!(
+                    "{} planned at {} is still pending, its planned time has passed",
+                    p.punch_type, p.planned_time
+                ),
+            });
+        }
+
+        while let Some(next) = self.plan.as_ref().unwrap().next_pending().cloned() {
+            let status = if Local::now() < next.planned_time {
+                self.next_action = format!("{} at {}", next.punch_type, next.planned_time);
+                match self.wait_until(&next.planned_time) {
+                    None => self.gated_punch(&next),
+                    // the plan may have been changed by a control command
+                    Some(Action::Continue) => continue,
+                    Some(Action::Replan) => return DayOutcome::Replan,
+                    Some(Action::Shutdown) => return DayOutcome::Shutdown,
+                }
+            } else {
+                self.catch_up_punch(&next)
+            };
+            // postponed to wait for presence
+            let status = match status {
+                Some(v) => v,
+                None => continue,
+            };
+
+            self.plan
+                .as_mut()
+                .unwrap()
+                .set_status(next.punch_type, status);
+            self.save_plan();
+        }
+
+        DayOutcome::Finished
+    }
+
+    /// Punches if the presence check passes, otherwise postpones the punch till the next check
+    /// and returns None, or skips it once the configured wait is over.
+    fn gated_punch(&mut self, planned: &PlannedPunch) -> Option<PunchStatus> {
+        let presence = match &self.config.presence {
+            Some(v) => v.clone(),
+            None => return Some(self.punch(planned.punch_type, Some(planned.planned_time))),
+        };
+        let absence = match check_presence(
+            &presence.check,
+            planned.punch_type,
+            planned.planned_time,
+            self.agent.is_dry_run(),
+        ) {
+            Ok(()) => return Some(self.punch(planned.punch_type, Some(planned.planned_time))),
+            Err(e) => e,
+        };
+
+        let now = Local::now();
+        // kept in the plan, so a restart does not start the wait over
+        let deadline = planned
+            .presence_deadline
+            .unwrap_or(now + Duration::minutes(presence.wait_minutes as i64));
+        let next_check = now + Duration::seconds(presence.interval_seconds as i64);
+
+        if next_check <= deadline {
+            info!(
+                "{} waiting for presence till {}, {}",
+                planned.punch_type, deadline, absence
+            );
+            let plan = self.plan.as_mut().unwrap();
+            let _ = plan.delay(planned.punch_type, next_check - planned.planned_time);
+            if let Some(p) = plan
+                .punches
+                .iter_mut()
+                .find(|p| p.punch_type == planned.punch_type)
+            {
+                p.presence_deadline = Some(deadline);
+            }
+            self.save_plan();
+            return None;
+        }
+
+        metrics::record_punch(planned.punch_type, "skipped");
+        warn!("{} skipped, no presence: {}", planned.punch_type, absence);
+        let reason = format!("no presence, {}", absence);
+        self.notifier.notify(Notification::PunchSkipped {
+            punch_type: planned.punch_type,
+            at: now,
+            reason: reason.clone(),
+        });
+        Some(PunchStatus::Skipped { at: now, reason })
+    }
+
+    /// Punches unless the before hook vetoes it, `scheduled_time` is passed to the hooks.
+    fn punch(
+        &mut self,
+        punch_type: PunchType,
+        scheduled_time: Option<DateTime<Local>>,
+    ) -> PunchStatus {
+        let status = punch_with_hooks(&self.agent, &self.config.hooks, punch_type, scheduled_time);
+
+        let notification = match &status {
+            PunchStatus::Done { at, response } => {
+                info!("{} done: {}", punch_type, response);
+                Notification::PunchSucceeded {
+                    punch_type,
+                    at: *at,
+                    response: response.clone(),
+                }
+            }
+            PunchStatus::Failed { at, error } => {
+                error!("{} failed: {}", punch_type, error);
+                Notification::PunchFailed {
+                    punch_type,
+                    at: *at,
+                    error: error.clone(),
+                }
+            }
+            PunchStatus::Skipped { at, reason } => Notification::PunchSkipped {
+                punch_type,
+                at: *at,
+                reason: reason.clone(),
+            },
+            PunchStatus::Pending => unreachable!(),
+        };
+        self.notifier.notify(notification);
+
+        status
+    }
+
+    /// Applies the catch up policy for a punch whose planned time has passed before it was executed.
+    fn catch_up_punch(&mut self, planned: &PlannedPunch) -> Option<PunchStatus> {
+        let now = Local::now();
+        let catch_up = match planned.punch_type {
+            PunchType::PunchIn => &self.config.catch_up.punch_in,
+            PunchType::PunchOut => &self.config.catch_up.punch_out,
+        };
+
+        let status = match catch_up.decide(&planned.shift_time, &now) {
+            CatchUpAction::PunchNow => {
+                info!(
+                    "{} catch up, current time has exceeded the scheduled auto punch time {} but is still within the catch up window",
+                    planned.punch_type, planned.planned_time
+                );
+                return self.gated_punch(planned);
+            }
+            CatchUpAction::Skip => {
+                metrics::record_punch(planned.punch_type, "skipped");
+                warn!(
+                    "{} skipped, because current time has exceeded the scheduled auto punch time {}",
+                    planned.punch_type, planned.planned_time
+                );
+                PunchStatus::Skipped {
+                    at: now,
+                    reason: "exceeded the scheduled auto punch time".to_string(),
+                }
+            }
+            CatchUpAction::Notify => {
+                metrics::record_punch(planned.punch_type, "skipped");
+                error!(
+                    "{} NOT punched, current time has exceeded the scheduled auto punch time {}, please punch manually",
+                    planned.punch_type, planned.planned_time
+                );
+                PunchStatus::Skipped {
+                    at: now,
+                    reason: "exceeded the scheduled auto punch time, manual punch needed"
+                        .to_string(),
+                }
+            }
+        };
+
+        if let PunchStatus::Skipped { at, reason } = &status {
+            self.notifier.notify(Notification::PunchSkipped {
+                punch_type: planned.punch_type,
+                at: *at,
+                reason: reason.clone(),
+            });
+        }
+        Some(status)
+    }
+}
+
+pub fn do_punch(agent: &ApolloAgent, hooks_config: &HooksConfig, punch_type: PunchType) {
+    match punch_with_hooks(agent, hooks_config, punch_type, None) {
+        PunchStatus::Done { response, .. } => {
+            println!("{}", serde_json::to_string_pretty(&response).unwrap())
+        }
+        PunchStatus::Failed { error, .. } => error!("{} failed: {}", punch_type, error),
+        // the veto is logged already
+        _ => {}
+    }
+}
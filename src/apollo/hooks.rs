@@ -0,0 +1,372 @@
+use std::io::{Read, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use serde_json::Value;
+use tracing::{info, warn};
+
+use super::agent::{ApolloAgent, PunchType};
+use super::config::HooksConfig;
+use super::metrics;
+use super::plan::PunchStatus;
+
+/// How often a running hook is checked for exit.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long the output of a hook is still read after it has been killed on timeout.
+const OUTPUT_GRACE: Duration = Duration::from_secs(1);
+
+/// What a hook receives, as JSON on stdin and as `APOLLO_*` environment variables:
+/// APOLLO_HOOK, APOLLO_PUNCH_TYPE, APOLLO_SCHEDULED_TIME, APOLLO_ACTUAL_TIME,
+/// APOLLO_RESPONSE, APOLLO_ERROR and APOLLO_DRY_RUN, unset values are empty.
+#[derive(Serialize, Debug, Clone)]
+pub struct HookContext {
+    pub hook: &'static str,
+    pub punch_type: PunchType,
+    /// planned time of the punch, unset for manual punches
+    pub scheduled_time: Option<DateTime<Local>>,
+    /// when the punch was sent, unset in before hooks
+    pub actual_time: Option<DateTime<Local>>,
+    pub response: Option<Value>,
+    pub error: Option<String>,
+    pub dry_run: bool,
+}
+
+impl HookContext {
+    fn env(&self) -> Vec<(&'static str, String)> {
+        let or_empty = |v: Option<String>| v.unwrap_or_default();
+        vec![
+            ("APOLLO_HOOK", self.hook.to_string()),
+            ("APOLLO_PUNCH_TYPE", self.punch_type.to_string()),
+            (
+                "APOLLO_SCHEDULED_TIME",
+                or_empty(self.scheduled_time.map(|t| t.to_rfc3339())),
+            ),
+            (
+                "APOLLO_ACTUAL_TIME",
+                or_empty(self.actual_time.map(|t| t.to_rfc3339())),
+            ),
+            (
+                "APOLLO_RESPONSE",
+                or_empty(self.response.as_ref().map(|v| v.to_string())),
+            ),
+            ("APOLLO_ERROR", or_empty(self.error.clone())),
+            ("APOLLO_DRY_RUN", self.dry_run.to_string()),
+        ]
+    }
+}
+
+/// Whether the child `pid` has exited, without reaping it, so its process group id
+/// can't be taken by another process yet.
+fn exited(pid: u32) -> std::io::Result<bool> {
+    // SAFETY: waitid only writes into the zeroed siginfo_t we own
+    unsafe {
+        let mut info: libc::siginfo_t = std::mem::zeroed();
+        if libc::waitid(
+            libc::P_PID,
+            pid as libc::id_t,
+            &mut info,
+            libc::WEXITED | libc::WNOHANG | libc::WNOWAIT,
+        ) != 0
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(info.si_pid() != 0)
+    }
+}
+
+/// Kills the process group of the hook, `pid` must not have been reaped yet.
+fn kill_group(pid: u32) {
+    // SAFETY: plain syscall, the group id is the pid of our still unreaped child
+    unsafe { libc::kill(-(pid as i32), libc::SIGKILL) };
+}
+
+/// Runs `command` with `sh -c`, fails on a non-zero exit or when it outlives `timeout`.
+/// Processes the hook started in its process group are killed once it exits.
+pub fn run_hook(command: &str, context: &HookContext, timeout: Duration) -> Result<(), String> {
+    info!("running {} hook: {}", context.hook, command);
+
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(context.env())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // own process group, so whatever the hook started is killed with it
+        .process_group(0)
+        .spawn()
+        .map_err(|e| format!("can't run {} hook: {}", context.hook, e))?;
+
+    // hooks may exit without reading stdin
+    let mut stdin = child.stdin.take().unwrap();
+    let _ = stdin.write_all(serde_json::to_string(context).unwrap().as_bytes());
+    drop(stdin);
+
+    // read the output on other threads, so a chatty hook never blocks on a full pipe
+    let (output_sender, outputs) = channel();
+    for (name, mut pipe) in [
+        (
+            "stdout",
+            Box::new(child.stdout.take().unwrap()) as Box<dyn Read + Send>,
+        ),
+        ("stderr", Box::new(child.stderr.take().unwrap())),
+    ] {
+        let output_sender = output_sender.clone();
+        thread::spawn(move || {
+            let mut output = String::new();
+            let _ = pipe.read_to_string(&mut output);
+            let _ = output_sender.send((name, output));
+        });
+    }
+    drop(output_sender);
+
+    let pid = child.id();
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match exited(pid) {
+            Ok(true) => {
+                // whatever the hook left running in the background goes with it
+                kill_group(pid);
+                break child
+                    .wait()
+                    .map_err(|e| format!("can't wait for {} hook: {}", context.hook, e));
+            }
+            Ok(false) if Instant::now() >= deadline => {
+                kill_group(pid);
+                let _ = child.wait();
+                break Err(format!(
+                    "{} hook killed after {}s",
+                    context.hook,
+                    timeout.as_secs()
+                ));
+            }
+            Ok(false) => thread::sleep(POLL_INTERVAL),
+            Err(e) => break Err(format!("can't wait for {} hook: {}", context.hook, e)),
+        }
+    };
+
+    // a process which left the group, e.g. with setsid, may keep the pipes open for good
+    let output_deadline = deadline.max(Instant::now() + OUTPUT_GRACE);
+    for _ in 0..2 {
+        let wait = output_deadline.saturating_duration_since(Instant::now());
+        match outputs.recv_timeout(wait) {
+            Ok((name, output)) => {
+                if !output.trim().is_empty() {
+                    info!("{} hook {}: {}", context.hook, name, output.trim_end());
+                }
+            }
+            Err(_) => {
+                warn!(
+                    "{} hook output still open after the hook exited, not waiting for it",
+                    context.hook
+                );
+                break;
+            }
+        }
+    }
+
+    let status = status?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("{} hook exited with {}", context.hook, status))
+    }
+}
+
+/// Runs the before hook of `punch_type`, an error means the punch is vetoed.
+pub fn before_punch(
+    hooks: &HooksConfig,
+    punch_type: PunchType,
+    scheduled_time: Option<DateTime<Local>>,
+    dry_run: bool,
+) -> Result<(), String> {
+    let (hook, command) = match punch_type {
+        PunchType::PunchIn => ("before_punch_in", &hooks.before_punch_in),
+        PunchType::PunchOut => ("before_punch_out", &hooks.before_punch_out),
+    };
+
+    match command {
+        Some(command) => run_hook(
+            command,
+            &HookContext {
+                hook,
+                punch_type,
+                scheduled_time,
+                actual_time: None,
+                response: None,
+                error: None,
+                dry_run,
+            },
+            Duration::from_secs(hooks.timeout_seconds),
+        ),
+        None => Ok(()),
+    }
+}
+
+/// Runs the after hook of `punch_type`, or the failure hook, failures are only logged.
+pub fn after_punch(
+    hooks: &HooksConfig,
+    punch_type: PunchType,
+    scheduled_time: Option<DateTime<Local>>,
+    actual_time: DateTime<Local>,
+    result: &Result<Value, String>,
+    dry_run: bool,
+) {
+    let (hook, command) = match (result, punch_type) {
+        (Err(_), _) => ("on_failure", &hooks.on_failure),
+        (Ok(_), PunchType::PunchIn) => ("after_punch_in", &hooks.after_punch_in),
+        (Ok(_), PunchType::PunchOut) => ("after_punch_out", &hooks.after_punch_out),
+    };
+
+    if let Some(command) = command {
+        let context = HookContext {
+            hook,
+            punch_type,
+            scheduled_time,
+            actual_time: Some(actual_time),
+            response: result.as_ref().ok().cloned(),
+            error: result.as_ref().err().cloned(),
+            dry_run,
+        };
+        if let Err(e) = run_hook(
+            command,
+            &context,
+            Duration::from_secs(hooks.timeout_seconds),
+        ) {
+            warn!("{}", e);
+        }
+    }
+}
+
+/// Punches between the before and after hooks, as every punch is sent, whoever asked for it.
+/// A veto of the before hook skips the punch, `scheduled_time` is passed to the hooks.
+pub fn punch_with_hooks(
+    agent: &ApolloAgent,
+    hooks: &HooksConfig,
+    punch_type: PunchType,
+    scheduled_time: Option<DateTime<Local>>,
+) -> PunchStatus {
+    let dry_run = agent.is_dry_run();
+    if let Err(e) = before_punch(hooks, punch_type, scheduled_time, dry_run) {
+        metrics::record_punch(punch_type, "skipped");
+        warn!("{} vetoed: {}", punch_type, e);
+        return PunchStatus::Skipped {
+            at: Local::now(),
+            reason: format!("vetoed, {}", e),
+        };
+    }
+
+    let at = Local::now();
+    let result = agent.punch_card(punch_type);
+    after_punch(hooks, punch_type, scheduled_time, at, &result, dry_run);
+
+    match result {
+        Ok(response) => PunchStatus::Done { at, response },
+        Err(error) => PunchStatus::Failed { at, error },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    use super::*;
+
+    fn context() -> HookContext {
+        HookContext {
+            hook: "after_punch_in",
+            punch_type: PunchType::PunchIn,
+            scheduled_time: Some(Local.with_ymd_and_hms(2023, 9, 23, 8, 55, 0).unwrap()),
+            actual_time: Some(Local.with_ymd_and_hms(2023, 9, 23, 8, 55, 2).unwrap()),
+            response: Some(json!({"Data": {"punchDate": "2023-09-23T00:55:02+00:00"}})),
+            error: None,
+            dry_run: false,
+        }
+    }
+
+    #[test]
+    fn test_hook_env_and_stdin() {
+        let timeout = Duration::from_secs(10);
+
+        run_hook(
+            r#"test "$APOLLO_HOOK" = after_punch_in \
+                && test "$APOLLO_PUNCH_TYPE" = PunchIn \
+                && test "$APOLLO_SCHEDULED_TIME" = 2023-09-23T08:55:00+08:00 \
+                && test -z "$APOLLO_ERROR" \
+                && test "$APOLLO_DRY_RUN" = false \
+                && echo "$APOLLO_RESPONSE" | grep -q punchDate"#,
+            &context(),
+            timeout,
+        )
+        .unwrap();
+
+        run_hook(
+            r#"grep -q '"actual_time":"2023-09-23T08:55:02+08:00"'"#,
+            &context(),
+            timeout,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_hook_failures() {
+        assert!(run_hook("exit 3", &context(), Duration::from_secs(10))
+            .unwrap_err()
+            .contains("exit status: 3"));
+
+        let started = Instant::now();
+        assert!(run_hook("sleep 10", &context(), Duration::from_millis(200))
+            .unwrap_err()
+            .contains("killed"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_hook_background_processes() {
+        // killed with the process group once the hook exits
+        let started = Instant::now();
+        run_hook(
+            "sleep 30 & echo started",
+            &context(),
+            Duration::from_secs(10),
+        )
+        .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // out of the process group, the pipes it holds are given up at the deadline
+        let started = Instant::now();
+        run_hook("setsid sleep 30 &", &context(), Duration::from_secs(2)).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_before_punch_veto() {
+        let hooks: HooksConfig =
+            serde_json::from_str(r#"{"before_punch_out": "test \"$APOLLO_DRY_RUN\" = true"}"#)
+                .unwrap();
+
+        assert!(before_punch(&hooks, PunchType::PunchIn, None, false).is_ok());
+        assert!(before_punch(&hooks, PunchType::PunchOut, None, true).is_ok());
+        assert!(before_punch(&hooks, PunchType::PunchOut, None, false).is_err());
+    }
+
+    #[test]
+    fn test_punch_with_hooks_veto() {
+        let hooks: HooksConfig =
+            serde_json::from_str(r#"{"before_punch_in": "echo on leave; exit 1"}"#).unwrap();
+        // never reaches Mayo
+        let agent = ApolloAgent::new("user", "password", "company");
+
+        match punch_with_hooks(&agent, &hooks, PunchType::PunchIn, None) {
+            PunchStatus::Skipped { reason, .. } => assert!(reason.starts_with("vetoed, ")),
+            status => panic!("not vetoed: {:?}", status),
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::process;
+
+/// Exclusive `flock` on a PID file, held for the whole lifetime of the daemon.
+pub struct InstanceLock {
+    // the lock is released when the file is closed
+    _file: File,
+}
+
+fn open_lock_file(path: &str) -> Result<File, String> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| format!("can't open lock file {}\nreason: {}", path, e))
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut content = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut content).ok()?;
+    content.trim().parse().ok()
+}
+
+impl InstanceLock {
+    pub fn acquire(path: &str) -> Result<Self, String> {
+        let mut file = open_lock_file(path)?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(format!(
+                    "another auto punch daemon (pid {}) is already running with the same config, lock file: {}",
+                    read_pid(&mut file).map_or("unknown".to_string(), |v| v.to_string()),
+                    path
+                ))
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(format!("can't lock {}\nreason: {}", path, e))
+            }
+        }
+
+        file.set_len(0)
+            .and_then(|_| file.rewind())
+            .and_then(|_| write!(file, "{}", process::id()))
+            .map_err(|e| format!("can't write pid into {}\nreason: {}", path, e))?;
+
+        Ok(InstanceLock { _file: file })
+    }
+
+    /// Returns the PID of the daemon holding the lock, None if no daemon is running.
+    pub fn holder(path: &str) -> Result<Option<u32>, String> {
+        let mut file = match File::open(path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("can't open lock file {}\nreason: {}", path, e)),
+        };
+
+        match file.try_lock_shared() {
+            Ok(()) => Ok(None),
+            Err(TryLockError::WouldBlock) => Ok(Some(read_pid(&mut file).unwrap_or(0))),
+            Err(TryLockError::Error(e)) => Err(format!("can't lock {}\nreason: {}", path, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_single_instance() {
+        let path = std::env::temp_dir()
+            .join(format!("apollo-lock-test-{}.lock", process::id()))
+            .to_string_lossy()
+            .to_string();
+
+        assert_eq!(InstanceLock::holder(&path).unwrap(), None);
+
+        let lock = InstanceLock::acquire(&path).unwrap();
+        assert_eq!(InstanceLock::holder(&path).unwrap(), Some(process::id()));
+
+        let err = InstanceLock::acquire(&path).err().unwrap();
+        assert!(err.contains(&format!("pid {}", process::id())));
+
+        drop(lock);
+        assert_eq!(InstanceLock::holder(&path).unwrap(), None);
+        assert!(InstanceLock::acquire(&path).is_ok());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,120 @@
+use std::path::Path;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use tracing::Level;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+#[derive(Clone, Copy, Debug, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogRotation {
+    Hourly,
+    Daily,
+    Never,
+}
+
+pub struct LogOptions {
+    /// -v count minus -q count, 0 logs at info level
+    pub verbosity: i8,
+    pub format: LogFormat,
+    /// log into this file as well, rotated by `rotation`
+    pub file: Option<String>,
+    pub rotation: LogRotation,
+    /// rotated log files kept, older ones are deleted
+    pub max_files: usize,
+}
+
+fn level_of(verbosity: i8) -> Level {
+    match verbosity {
+        i8::MIN..=-2 => Level::ERROR,
+        -1 => Level::WARN,
+        0 => Level::INFO,
+        1 => Level::DEBUG,
+        _ => Level::TRACE,
+    }
+}
+
+fn layer_of(
+    format: LogFormat,
+    writer: BoxMakeWriter,
+    ansi: bool,
+) -> Box<dyn Layer<Registry> + Send + Sync> {
+    match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer()
+            .with_writer(writer)
+            .with_ansi(ansi)
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_writer(writer)
+            .boxed(),
+    }
+}
+
+/// Sets up the global subscriber logging to stderr, and into a rotating file if asked.
+/// `RUST_LOG` overrides the level picked by -v/-q. The returned guard flushes the file on drop.
+pub fn init(options: &LogOptions) -> Result<Option<WorkerGuard>, String> {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(level_of(options.verbosity).to_string()));
+
+    let mut layers = vec![layer_of(
+        options.format,
+        BoxMakeWriter::new(std::io::stderr),
+        true,
+    )];
+
+    let guard = match &options.file {
+        Some(file) => {
+            let path = Path::new(file);
+            let appender = RollingFileAppender::builder()
+                .rotation(match options.rotation {
+                    LogRotation::Hourly => Rotation::HOURLY,
+                    LogRotation::Daily => Rotation::DAILY,
+                    LogRotation::Never => Rotation::NEVER,
+                })
+                .filename_prefix(path.file_name().unwrap_or_default().to_string_lossy())
+                .max_log_files(options.max_files)
+                .build(path.parent().unwrap_or(Path::new(".")))
+                .map_err(|e| format!("can't open log file {}\nreason: {}", file, e))?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+
+            layers.push(layer_of(options.format, BoxMakeWriter::new(writer), false));
+            Some(guard)
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(layers)
+        .with(filter)
+        .try_init()
+        .map_err(|e| e.to_string())?;
+
+    Ok(guard)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level_of() {
+        assert_eq!(level_of(-3), Level::ERROR);
+        assert_eq!(level_of(-1), Level::WARN);
+        assert_eq!(level_of(0), Level::INFO);
+        assert_eq!(level_of(1), Level::DEBUG);
+        assert_eq!(level_of(5), Level::TRACE);
+    }
+}
@@ -0,0 +1,178 @@
+use std::io::Cursor;
+use std::sync::LazyLock;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local};
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, TextEncoder,
+};
+use tiny_http::{Header, Response};
+use tracing::info;
+
+use super::agent::PunchType;
+
+static LOGIN_ATTEMPTS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("apollo_login_attempts_total", "Login attempts").unwrap()
+});
+
+static LOGIN_FAILURES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("apollo_login_failures_total", "Failed login attempts").unwrap()
+});
+
+static PUNCH_ATTEMPTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "apollo_punch_attempts_total",
+        "Punches by punch type and outcome (success, failure, dry_run, skipped)",
+        &["punch_type", "outcome"]
+    )
+    .unwrap()
+});
+
+static LAST_SUCCESSFUL_PUNCH: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "apollo_last_successful_punch_timestamp_seconds",
+        "Unix time of the last successful punch",
+        &["punch_type"]
+    )
+    .unwrap()
+});
+
+static NEXT_SCHEDULED_PUNCH: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "apollo_next_scheduled_punch_timestamp_seconds",
+        "Unix time of the next planned punch, 0 if nothing is pending",
+        &["punch_type"]
+    )
+    .unwrap()
+});
+
+static HTTP_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "apollo_http_request_duration_seconds",
+        "Latency of requests made to Mayo, by endpoint",
+        &["endpoint"]
+    )
+    .unwrap()
+});
+
+static UPTIME: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "apollo_daemon_uptime_seconds",
+        "Seconds since the daemon started"
+    )
+    .unwrap()
+});
+
+/// Registers every metric, so they are exported before their first observation.
+pub fn init() {
+    LazyLock::force(&LOGIN_ATTEMPTS);
+    LazyLock::force(&LOGIN_FAILURES);
+    LazyLock::force(&PUNCH_ATTEMPTS);
+    LazyLock::force(&LAST_SUCCESSFUL_PUNCH);
+    LazyLock::force(&NEXT_SCHEDULED_PUNCH);
+    LazyLock::force(&HTTP_REQUEST_DURATION);
+    LazyLock::force(&UPTIME);
+}
+
+pub fn record_login(success: bool) {
+    LOGIN_ATTEMPTS.inc();
+    if !success {
+        LOGIN_FAILURES.inc();
+    }
+}
+
+pub fn observe_http_request(endpoint: &str, duration: Duration) {
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[endpoint])
+        .observe(duration.as_secs_f64());
+}
+
+pub fn record_punch(punch_type: PunchType, outcome: &str) {
+    PUNCH_ATTEMPTS
+        .with_label_values(&[&punch_type.to_string(), outcome])
+        .inc();
+
+    if outcome == "success" {
+        LAST_SUCCESSFUL_PUNCH
+            .with_label_values(&[&punch_type.to_string()])
+            .set(Local::now().timestamp());
+    }
+}
+
+pub fn set_next_punch(punch_type: PunchType, time: Option<&DateTime<Local>>) {
+    NEXT_SCHEDULED_PUNCH
+        .with_label_values(&[&punch_type.to_string()])
+        .set(time.map_or(0, |t| t.timestamp()));
+}
+
+/// All registered metrics in the Prometheus text format.
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+/// Serves `GET /metrics` on `listen` in a background thread.
+pub fn spawn_metrics_server(listen: &str) -> Result<(), String> {
+    let server = tiny_http::Server::http(listen)
+        .map_err(|e| format!("can't listen on {}\nreason: {}", listen, e))?;
+    let started = Instant::now();
+    info!("metrics available on http://{}/metrics", listen);
+
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let response = if request.url() == "/metrics" {
+                UPTIME.set(started.elapsed().as_secs() as i64);
+                Response::new(
+                    200.into(),
+                    vec![
+                        Header::from_bytes("Content-Type", TextEncoder::new().format_type())
+                            .unwrap(),
+                    ],
+                    Cursor::new(render().into_bytes()),
+                    None,
+                    None,
+                )
+            } else {
+                Response::from_string("not found").with_status_code(404)
+            };
+            let _ = request.respond(response);
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        init();
+        record_login(false);
+        record_punch(PunchType::PunchIn, "success");
+        set_next_punch(PunchType::PunchOut, Some(&Local::now()));
+        observe_http_request("/api/checkIn/punch/web", Duration::from_millis(250));
+
+        let text = render();
+        assert!(text.contains("apollo_login_attempts_total"));
+        assert!(text.contains("apollo_login_failures_total"));
+        assert!(text.contains("apollo_daemon_uptime_seconds"));
+        assert!(
+            text.contains(r#"apollo_punch_attempts_total{outcome="success",punch_type="PunchIn"}"#)
+        );
+        assert!(text
+            .contains(r#"apollo_last_successful_punch_timestamp_seconds{punch_type="PunchIn"}"#));
+        assert!(text
+            .contains(r#"apollo_next_scheduled_punch_timestamp_seconds{punch_type="PunchOut"}"#));
+        assert!(text.contains(
+            r#"apollo_http_request_duration_seconds_count{endpoint="/api/checkIn/punch/web"} "#
+        ));
+    }
+}
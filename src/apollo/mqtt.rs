@@ -0,0 +1,419 @@
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::Duration;
+
+use rumqttc::{Client, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::{json, Value};
+use tracing::{debug, info, warn};
+
+use super::config::{ConfigPayload, MqttConfig};
+use super::control::ControlCommand;
+use super::daemon::DaemonEvent;
+use super::plan::DayPlan;
+use super::workday_schedule::WorkdaySchedule;
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+/// Wait between reconnects while the broker is unreachable.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// How long a command waits for the daemon, which may be in the middle of a punch request.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Topics under `<topic_prefix>/<node_id>`.
+#[derive(Clone)]
+struct Topics {
+    base: String,
+}
+
+impl Topics {
+    fn get(&self, name: &str) -> String {
+        format!("{}/{}", self.base, name)
+    }
+}
+
+/// Publishes the daemon's state to an MQTT broker, and forwards the control commands
+/// received on `<topic_prefix>/<node_id>/command` into the daemon, replies go to
+/// `.../command/reply`. Publishing never blocks, messages are dropped while the broker is away.
+pub struct MqttBridge {
+    client: Client,
+    topics: Topics,
+}
+
+/// Lower case letters, digits and underscores only, as Home Assistant expects in ids.
+fn sanitize_id(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+pub fn node_id(config: &ConfigPayload) -> String {
+    let mqtt = config.mqtt.as_ref();
+    match mqtt.and_then(|m| m.node_id.as_ref()) {
+        Some(id) => sanitize_id(id),
+        None => sanitize_id(&format!("apollo_{}_{}", config.company, config.username)),
+    }
+}
+
+/// Home Assistant discovery topics and payloads, showing the daemon as a device with
+/// sensors for the schedule and punches, and buttons sending control commands.
+fn discovery_messages(discovery_prefix: &str, topics: &Topics, node: &str) -> Vec<(String, Value)> {
+    let device = json!({
+        "identifiers": [node],
+        "name": format!("Apollo {}", node),
+        "manufacturer": "apollo-hr-agent-rs",
+    });
+
+    let entities = [
+        (
+            "binary_sensor",
+            "work_day",
+            json!({
+                "name": "Work day",
+                "state_topic": topics.get("schedule"),
+                "value_template": "{{ 'ON' if value_json.work_day else 'OFF' }}",
+                "json_attributes_topic": topics.get("schedule"),
+            }),
+        ),
+        (
+            "sensor",
+            "schedule",
+            json!({
+                "name": "Schedule",
+                "state_topic": topics.get("schedule"),
+                "value_template": "{{ value_json.description }}",
+                "json_attributes_topic": topics.get("schedule"),
+            }),
+        ),
+        (
+            "sensor",
+            "next_punch",
+            json!({
+                "name": "Next punch",
+                "device_class": "timestamp",
+                "state_topic": topics.get("next_punch"),
+                "value_template": "{{ value_json.planned_time }}",
+                "json_attributes_topic": topics.get("next_punch"),
+            }),
+        ),
+        (
+            "sensor",
+            "last_punch",
+            json!({
+                "name": "Last punch",
+                "state_topic": topics.get("last_punch"),
+                "value_template": "{{ value_json.punch_type }} {{ value_json.status }}",
+                "json_attributes_topic": topics.get("last_punch"),
+            }),
+        ),
+        (
+            "button",
+            "punch_in",
+            button("Punch in", "punch-now in", topics),
+        ),
+        (
+            "button",
+            "punch_out",
+            button("Punch out", "punch-now out", topics),
+        ),
+        (
+            "button",
+            "skip_today",
+            button("Skip today", "skip today", topics),
+        ),
+    ];
+
+    entities
+        .into_iter()
+        .map(|(component, object, mut payload)| {
+            payload["unique_id"] = json!(format!("{}_{}", node, object));
+            payload["availability_topic"] = json!(topics.get("availability"));
+            payload["device"] = device.clone();
+            (
+                format!(
+                    "{}/{}/{}/{}/config",
+                    discovery_prefix, component, node, object
+                ),
+                payload,
+            )
+        })
+        .collect()
+}
+
+fn button(name: &str, command: &str, topics: &Topics) -> Value {
+    json!({
+        "name": name,
+        "command_topic": topics.get("command"),
+        "payload_press": command,
+    })
+}
+
+/// The next pending punch, with nulls when nothing is pending.
+fn next_punch_payload(plan: &DayPlan) -> Value {
+    match plan.next_pending() {
+        Some(p) => json!({ "punch_type": p.punch_type, "planned_time": p.planned_time }),
+        None => json!({ "punch_type": null, "planned_time": null }),
+    }
+}
+
+/// The latest handled punch of `plan`.
+fn last_punch_payload(plan: &DayPlan) -> Option<Value> {
+    plan.punches
+        .iter()
+        .rev()
+        .find(|p| !p.is_pending())
+        .map(|p| serde_json::to_value(p).unwrap())
+}
+
+impl MqttBridge {
+    pub fn spawn(config: &ConfigPayload, sender: Sender<DaemonEvent>) -> Result<Self, String> {
+        let mqtt: &MqttConfig = config
+            .mqtt
+            .as_ref()
+            .ok_or_else(|| "no mqtt section in config".to_string())?;
+        let node = node_id(config);
+        let topics = Topics {
+            base: format!("{}/{}", mqtt.topic_prefix.trim_end_matches('/'), node),
+        };
+
+        let mut options = MqttOptions::new(node.clone(), &mqtt.host, mqtt.port);
+        options.set_keep_alive(KEEP_ALIVE);
+        options.set_last_will(LastWill::new(
+            topics.get("availability"),
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        if let Some(username) = &mqtt.username {
+            options.set_credentials(username, mqtt.password.clone().unwrap_or_default());
+        }
+
+        let (client, mut connection) = Client::new(options, 64);
+        let discovery = if mqtt.discovery {
+            discovery_messages(mqtt.discovery_prefix.trim_end_matches('/'), &topics, &node)
+        } else {
+            Vec::new()
+        };
+
+        let bridge = MqttBridge {
+            client: client.clone(),
+            topics: topics.clone(),
+        };
+        let address = format!("{}:{}", mqtt.host, mqtt.port);
+
+        thread::spawn(move || {
+            for event in connection.iter() {
+                match event {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        info!("connected to mqtt broker {}", address);
+                        // subscriptions are gone with a clean session, and the will may have marked us offline
+                        let _ = client.try_subscribe(topics.get("command"), QoS::AtLeastOnce);
+                        for (topic, payload) in &discovery {
+                            let _ = client.try_publish(
+                                topic,
+                                QoS::AtLeastOnce,
+                                true,
+                                payload.to_string(),
+                            );
+                        }
+                        let _ = client.try_publish(
+                            topics.get("availability"),
+                            QoS::AtLeastOnce,
+                            true,
+                            "online",
+                        );
+                    }
+                    Ok(Event::Incoming(Packet::Publish(p))) if p.topic == topics.get("command") => {
+                        let line = String::from_utf8_lossy(&p.payload).to_string();
+                        forward_command(&line, &sender, &client, &topics);
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!("mqtt broker {}: {}", address, e);
+                        thread::sleep(RECONNECT_DELAY);
+                    }
+                }
+            }
+        });
+
+        Ok(bridge)
+    }
+
+    fn publish(&self, name: &str, payload: &Value) {
+        if let Err(e) = self.client.try_publish(
+            self.topics.get(name),
+            QoS::AtLeastOnce,
+            true,
+            payload.to_string(),
+        ) {
+            debug!("mqtt {} not published: {}", name, e);
+        }
+    }
+
+    pub fn publish_schedule(&self, schedule: &WorkdaySchedule) {
+        self.publish("schedule", &schedule.to_json());
+    }
+
+    pub fn publish_plan(&self, plan: &DayPlan) {
+        self.publish("next_punch", &next_punch_payload(plan));
+        if let Some(last) = last_punch_payload(plan) {
+            self.publish("last_punch", &last);
+        }
+    }
+}
+
+impl Drop for MqttBridge {
+    fn drop(&mut self) {
+        self.publish("availability", &json!("offline"));
+        let _ = self.client.try_disconnect();
+    }
+}
+
+/// Hands a command to the daemon and publishes its reply, waiting on another thread
+/// so the connection keeps being polled.
+fn forward_command(line: &str, sender: &Sender<DaemonEvent>, client: &Client, topics: &Topics) {
+    info!("mqtt command: {}", line);
+    let reply_topic = topics.get("command/reply");
+
+    let command = match line.parse::<ControlCommand>() {
+        Ok(v) => v,
+        Err(e) => {
+            let _ = client.try_publish(
+                reply_topic,
+                QoS::AtLeastOnce,
+                false,
+                format!("error: {}", e),
+            );
+            return;
+        }
+    };
+
+    let (reply_sender, reply_receiver) = channel();
+    if sender
+        .send(DaemonEvent::Control(command, reply_sender))
+        .is_err()
+    {
+        return;
+    }
+
+    let client = client.clone();
+    thread::spawn(move || {
+        let reply = reply_receiver
+            .recv_timeout(REPLY_TIMEOUT)
+            .unwrap_or_else(|e| format!("error: no reply from daemon, {}", e));
+        let _ = client.try_publish(reply_topic, QoS::AtLeastOnce, false, reply);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Local, TimeZone};
+
+    use super::*;
+    use crate::apollo::agent::PunchType;
+    use crate::apollo::plan::PunchStatus;
+
+    #[test]
+    fn test_node_id() {
+        let mut config: ConfigPayload = serde_json::from_str(
+            r#"{"username": "1234", "password": "p", "company": "ACME Inc.", "mqtt": {"host": "localhost"}}"#,
+        )
+        .unwrap();
+        assert_eq!(node_id(&config), "apollo_acme_inc__1234");
+
+        config.mqtt.as_mut().unwrap().node_id = Some("Office-PC".to_string());
+        assert_eq!(node_id(&config), "office_pc");
+        assert!(config.mqtt.as_ref().unwrap().discovery);
+        assert_eq!(
+            config.mqtt.as_ref().unwrap().discovery_prefix,
+            "homeassistant"
+        );
+
+        let config: ConfigPayload = toml::from_str(
+            r#"
+            username = "1234"
+            password = "p"
+            company = "c"
+            [mqtt]
+            host = "localhost"
+            discovery = false
+            "#,
+        )
+        .unwrap();
+        assert!(!config.mqtt.unwrap().discovery);
+    }
+
+    #[test]
+    fn test_discovery_messages() {
+        let topics = Topics {
+            base: "apollo/node".to_string(),
+        };
+        let messages = discovery_messages("homeassistant", &topics, "node");
+
+        let (topic, work_day) = &messages[0];
+        assert_eq!(topic, "homeassistant/binary_sensor/node/work_day/config");
+        assert_eq!(work_day["state_topic"], "apollo/node/schedule");
+        assert_eq!(work_day["unique_id"], "node_work_day");
+        assert_eq!(work_day["availability_topic"], "apollo/node/availability");
+        assert_eq!(work_day["device"]["identifiers"], json!(["node"]));
+
+        let (topic, punch_in) = messages
+            .iter()
+            .find(|(t, _)| t.ends_with("/punch_in/config"))
+            .unwrap();
+        assert_eq!(topic, "homeassistant/button/node/punch_in/config");
+        assert_eq!(punch_in["command_topic"], "apollo/node/command");
+        // button payloads are valid control commands
+        for (_, payload) in messages.iter().filter(|(t, _)| t.contains("/button/")) {
+            assert!(payload["payload_press"]
+                .as_str()
+                .unwrap()
+                .parse::<ControlCommand>()
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn test_plan_payloads() {
+        let at = |h| Local.with_ymd_and_hms(2023, 9, 23, h, 0, 0).unwrap();
+        let mut plan = DayPlan {
+            date: at(0).date_naive(),
+            punches: vec![],
+            summary_sent: false,
+        };
+        for (punch_type, hour) in [(PunchType::PunchIn, 9), (PunchType::PunchOut, 18)] {
+            plan.punches.push(crate::apollo::plan::PlannedPunch {
+                punch_type,
+                shift_time: at(hour),
+                planned_time: at(hour),
+                status: PunchStatus::Pending,
+                presence_deadline: None,
+            });
+        }
+
+        assert_eq!(next_punch_payload(&plan)["punch_type"], "PunchIn");
+        assert!(last_punch_payload(&plan).is_none());
+
+        plan.set_status(
+            PunchType::PunchIn,
+            PunchStatus::Done {
+                at: at(9),
+                response: json!({}),
+            },
+        );
+        assert_eq!(next_punch_payload(&plan)["punch_type"], "PunchOut");
+        let last = last_punch_payload(&plan).unwrap();
+        assert_eq!(last["punch_type"], "PunchIn");
+        assert_eq!(last["status"], "done");
+
+        plan.skip_pending("skipped by ctl");
+        assert_eq!(next_punch_payload(&plan)["planned_time"], Value::Null);
+        assert_eq!(last_punch_payload(&plan).unwrap()["status"], "skipped");
+    }
+}
@@ -0,0 +1,665 @@
+use std::fmt::Display;
+use std::sync::mpsc::{channel, Sender};
+use std::thread::{self, sleep, JoinHandle};
+use std::time::Duration;
+
+use chrono::{DateTime, Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{debug, error, warn};
+
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+
+use super::agent::PunchType;
+use super::config::{ConfigPayload, EmailConfig, SmtpSecurity, WebhookConfig};
+use super::plan::DayPlan;
+use super::telegram::TelegramSink;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Delay before the first webhook retry, doubled on every further retry.
+const WEBHOOK_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Something the daemon wants the user to know about.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Notification {
+    PunchSucceeded {
+        punch_type: PunchType,
+        at: DateTime<Local>,
+        response: Value,
+    },
+    PunchFailed {
+        punch_type: PunchType,
+        at: DateTime<Local>,
+        error: String,
+    },
+    PunchSkipped {
+        punch_type: PunchType,
+        at: DateTime<Local>,
+        reason: String,
+    },
+    LoginFailed {
+        at: DateTime<Local>,
+        error: String,
+    },
+    /// the schedule changed after planning, or looks wrong
+    ScheduleAnomaly {
+        date: NaiveDate,
+        detail: String,
+    },
+    /// the punches arranged for a work day
+    DayPlanned {
+        plan: DayPlan,
+    },
+}
+
+/// Notification names used to filter what a sink receives.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    PunchSucceeded,
+    PunchFailed,
+    PunchSkipped,
+    LoginFailed,
+    ScheduleAnomaly,
+    DayPlanned,
+}
+
+impl Notification {
+    pub fn kind(&self) -> NotificationKind {
+        match self {
+            Notification::PunchSucceeded { .. } => NotificationKind::PunchSucceeded,
+            Notification::PunchFailed { .. } => NotificationKind::PunchFailed,
+            Notification::PunchSkipped { .. } => NotificationKind::PunchSkipped,
+            Notification::LoginFailed { .. } => NotificationKind::LoginFailed,
+            Notification::ScheduleAnomaly { .. } => NotificationKind::ScheduleAnomaly,
+            Notification::DayPlanned { .. } => NotificationKind::DayPlanned,
+        }
+    }
+
+    /// One line summary, e.g. for mail subjects.
+    pub fn title(&self) -> String {
+        match self {
+            Notification::PunchSucceeded { punch_type, .. } => format!("{} done", punch_type),
+            Notification::PunchFailed { punch_type, .. } => format!("{} failed", punch_type),
+            Notification::PunchSkipped { punch_type, .. } => format!("{} skipped", punch_type),
+            Notification::LoginFailed { .. } => "login failed".to_string(),
+            Notification::ScheduleAnomaly { date, .. } => format!("schedule anomaly on {}", date),
+            Notification::DayPlanned { plan } => format!("auto punch plan of {}", plan.date),
+        }
+    }
+}
+
+impl Display for Notification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Notification::PunchSucceeded { punch_type, at, .. } => {
+                write!(f, "{} done at {}", punch_type, at)
+            }
+            Notification::PunchFailed {
+                punch_type,
+                at,
+                error,
+            } => write!(f, "{} failed at {}: {}", punch_type, at, error),
+            Notification::PunchSkipped {
+                punch_type,
+                at,
+                reason,
+            } => write!(f, "{} skipped at {}: {}", punch_type, at, reason),
+            Notification::LoginFailed { at, error } => {
+                write!(f, "login failed at {}: {}", at, error)
+            }
+            Notification::ScheduleAnomaly { date, detail } => {
+                write!(f, "schedule anomaly on {}: {}", date, detail)
+            }
+            Notification::DayPlanned { plan } => write!(f, "{}", plan),
+        }
+    }
+}
+
+/// What sinks receive, the `events` they list or all of them when empty, but the daily
+/// summary only when `daily_summary` is set.
+pub fn wants_event(
+    events: &[NotificationKind],
+    daily_summary: bool,
+    kind: NotificationKind,
+) -> bool {
+    if kind == NotificationKind::DayPlanned {
+        return daily_summary;
+    }
+    events.is_empty() || events.contains(&kind)
+}
+
+/// A destination notifications are delivered to.
+pub trait Sink: Send {
+    /// Name used in logs.
+    fn name(&self) -> String;
+
+    fn wants(&self, _kind: NotificationKind) -> bool {
+        true
+    }
+
+    fn send(&self, notification: &Notification) -> Result<(), String>;
+}
+
+/// Delivers notifications to every sink on a background thread, so slow or retried
+/// deliveries never hold up a punch. Pending notifications are flushed on drop.
+pub struct Notifier {
+    sender: Option<Sender<Notification>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Notifier {
+    pub fn new(sinks: Vec<Box<dyn Sink>>) -> Self {
+        if sinks.is_empty() {
+            return Notifier {
+                sender: None,
+                worker: None,
+            };
+        }
+
+        let (sender, receiver) = channel::<Notification>();
+        let worker = thread::spawn(move || {
+            for notification in receiver {
+                for sink in sinks.iter().filter(|s| s.wants(notification.kind())) {
+                    match sink.send(&notification) {
+                        Ok(()) => debug!("notified via {}: {}", sink.name(), notification),
+                        Err(e) => error!("notification via {} failed: {}", sink.name(), e),
+                    }
+                }
+            }
+        });
+
+        Notifier {
+            sender: Some(sender),
+            worker: Some(worker),
+        }
+    }
+
+    pub fn from_config(config: &ConfigPayload) -> Result<Self, String> {
+        let mut sinks: Vec<Box<dyn Sink>> = vec![];
+        for webhook in &config.notifications.webhooks {
+            sinks.push(Box::new(WebhookSink::new(webhook.clone())));
+        }
+        for email in &config.notifications.emails {
+            sinks.push(Box::new(EmailSink::new(email.clone())?));
+        }
+        if let Some(telegram) = &config.telegram {
+            sinks.push(Box::new(TelegramSink::new(telegram.clone())));
+        }
+        Ok(Notifier::new(sinks))
+    }
+
+    pub fn notify(&self, notification: Notification) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(notification);
+        }
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Posts notifications as JSON to an URL.
+pub struct WebhookSink {
+    config: WebhookConfig,
+    client: reqwest::blocking::Client,
+    retry_delay: Duration,
+}
+
+impl WebhookSink {
+    pub fn new(config: WebhookConfig) -> Self {
+        WebhookSink {
+            config,
+            client: reqwest::blocking::Client::builder()
+                .timeout(WEBHOOK_TIMEOUT)
+                .build()
+                .unwrap(),
+            retry_delay: WEBHOOK_RETRY_DELAY,
+        }
+    }
+
+    fn post(&self, body: &str) -> Result<(), (bool, String)> {
+        let mut builder = self
+            .client
+            .post(&self.config.url)
+            .header("Content-Type", "application/json")
+            .body(body.to_string());
+        for (name, value) in &self.config.headers {
+            builder = builder.header(name, value);
+        }
+
+        let resp = builder.send().map_err(|e| (true, e.to_string()))?;
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(());
+        }
+
+        // other client errors won't go away by retrying
+        let retryable = status.is_server_error() || status.as_u16() == 429;
+        Err((
+            retryable,
+            format!("[{}] {}", status.as_u16(), resp.text().unwrap_or_default()),
+        ))
+    }
+}
+
+impl Sink for WebhookSink {
+    fn name(&self) -> String {
+        format!("webhook {}", self.config.url)
+    }
+
+    fn wants(&self, kind: NotificationKind) -> bool {
+        wants_event(&self.config.events, self.config.daily_summary, kind)
+    }
+
+    fn send(&self, notification: &Notification) -> Result<(), String> {
+        let body = match &self.config.body_template {
+            Some(template) => render_template(template, notification)?,
+            None => serde_json::to_string(notification).unwrap(),
+        };
+
+        let mut delay = self.retry_delay;
+        let mut attempt = 0;
+        loop {
+            match self.post(&body) {
+                Ok(()) => return Ok(()),
+                Err((true, e)) if attempt < self.config.retries => {
+                    attempt += 1;
+                    warn!(
+                        "webhook {} failed: {}, retry {}/{} in {:?}",
+                        self.config.url, e, attempt, self.config.retries, delay
+                    );
+                    sleep(delay);
+                    delay *= 2;
+                }
+                Err((_, e)) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Sends notifications as plain text mails over SMTP.
+pub struct EmailSink {
+    config: EmailConfig,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    transport: SmtpTransport,
+}
+
+impl EmailSink {
+    pub fn new(config: EmailConfig) -> Result<Self, String> {
+        let parse = |address: &String| {
+            address
+                .parse::<Mailbox>()
+                .map_err(|e| format!("invalid mail address {}: {}", address, e))
+        };
+        let from = parse(&config.from)?;
+        let to = config.to.iter().map(parse).collect::<Result<Vec<_>, _>>()?;
+        if to.is_empty() {
+            return Err(format!("no recipients for mails via {}", config.host));
+        }
+
+        let builder = match config.security {
+            SmtpSecurity::Starttls => SmtpTransport::starttls_relay(&config.host),
+            SmtpSecurity::Tls => SmtpTransport::relay(&config.host),
+            SmtpSecurity::None => Ok(SmtpTransport::builder_dangerous(&config.host).port(25)),
+        }
+        .map_err(|e| format!("can't set up SMTP to {}: {}", config.host, e))?;
+
+        let mut builder = builder.timeout(Some(SMTP_TIMEOUT));
+        if let Some(port) = config.port {
+            builder = builder.port(port);
+        }
+        if let Some(username) = &config.username {
+            builder = builder.credentials(Credentials::new(
+                username.clone(),
+                config.password.clone().unwrap_or_default(),
+            ));
+        }
+
+        Ok(EmailSink {
+            from,
+            to,
+            transport: builder.build(),
+            config,
+        })
+    }
+
+    fn body(notification: &Notification) -> String {
+        match notification {
+            Notification::PunchSucceeded { response, .. } => format!(
+                "{}\n\n{}",
+                notification,
+                serde_json::to_string_pretty(response).unwrap()
+            ),
+            _ => notification.to_string(),
+        }
+    }
+
+    fn message(&self, notification: &Notification) -> Result<Message, String> {
+        let mut builder = Message::builder()
+            .from(self.from.clone())
+            .subject(format!("[apollo] {}", notification.title()))
+            .header(ContentType::TEXT_PLAIN);
+        for to in &self.to {
+            builder = builder.to(to.clone());
+        }
+        builder
+            .body(EmailSink::body(notification))
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl Sink for EmailSink {
+    fn name(&self) -> String {
+        format!("email {}", self.config.host)
+    }
+
+    fn wants(&self, kind: NotificationKind) -> bool {
+        wants_event(&self.config.events, self.config.daily_summary, kind)
+    }
+
+    fn send(&self, notification: &Notification) -> Result<(), String> {
+        self.transport
+            .send(&self.message(notification)?)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Replaces `{{field}}` placeholders with the fields of `notification`, plus `{{message}}`
+/// for its human readable form. Strings are JSON escaped without quotes, so they fit into
+/// a quoted template value, other values are inserted as JSON.
+fn render_template(template: &str, notification: &Notification) -> Result<String, String> {
+    let mut fields = match serde_json::to_value(notification).unwrap() {
+        Value::Object(map) => map,
+        _ => unreachable!(),
+    };
+    fields.insert(
+        "message".to_string(),
+        Value::String(notification.to_string()),
+    );
+
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find("}}")
+            .ok_or_else(|| "unclosed {{ in body_template".to_string())?;
+
+        match fields.get(after[..end].trim()) {
+            Some(Value::String(s)) => {
+                let quoted = Value::String(s.clone()).to_string();
+                rendered.push_str(&quoted[1..quoted.len() - 1]);
+            }
+            Some(v) => rendered.push_str(&v.to_string()),
+            None => {}
+        }
+        rest = &after[end + 2..];
+    }
+    rendered.push_str(rest);
+
+    serde_json::from_str::<Value>(&rendered).map_err(|e| {
+        format!(
+            "body_template is not json after rendering: {}\n{}",
+            e, rendered
+        )
+    })?;
+    Ok(rendered)
+}
+
+/// Renders `template` with a sample notification, so a broken template shows up before
+/// the first real one.
+pub fn check_template(template: &str) -> Result<(), String> {
+    render_template(
+        template,
+        &Notification::LoginFailed {
+            at: Local::now(),
+            error: "sample error".to_string(),
+        },
+    )
+    .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    use super::*;
+
+    fn failed() -> Notification {
+        Notification::PunchFailed {
+            punch_type: PunchType::PunchOut,
+            at: Local.with_ymd_and_hms(2023, 9, 23, 18, 5, 0).unwrap(),
+            error: "[400][Failed] {\"error\":\"打卡失敗\"}".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_render_template() {
+        let rendered = render_template(
+            r#"{"text": "{{message}}", "kind": "{{ event }}", "unknown": "{{nope}}"}"#,
+            &failed(),
+        )
+        .unwrap();
+        let rendered: Value = serde_json::from_str(&rendered).unwrap();
+
+        assert_eq!(
+            rendered,
+            json!({
+                "text": "PunchOut failed at 2023-09-23 18:05:00 +08:00: [400][Failed] {\"error\":\"打卡失敗\"}",
+                "kind": "punch_failed",
+                "unknown": ""
+            })
+        );
+
+        assert!(render_template(r#"{"text": {{message}}}"#, &failed()).is_err());
+        assert!(render_template(r#"{"text": "{{message"}"#, &failed()).is_err());
+    }
+
+    #[test]
+    fn test_notifier_filters_and_flushes() {
+        struct Collect(Arc<Mutex<Vec<Notification>>>);
+        impl Sink for Collect {
+            fn name(&self) -> String {
+                "collect".to_string()
+            }
+            fn wants(&self, kind: NotificationKind) -> bool {
+                kind != NotificationKind::ScheduleAnomaly
+            }
+            fn send(&self, notification: &Notification) -> Result<(), String> {
+                self.0.lock().unwrap().push(notification.clone());
+                Ok(())
+            }
+        }
+
+        let received = Arc::new(Mutex::new(vec![]));
+        let notifier = Notifier::new(vec![Box::new(Collect(received.clone()))]);
+        notifier.notify(Notification::ScheduleAnomaly {
+            date: NaiveDate::from_ymd_opt(2023, 9, 23).unwrap(),
+            detail: "shift changed".to_string(),
+        });
+        notifier.notify(failed());
+        drop(notifier);
+
+        assert_eq!(*received.lock().unwrap(), vec![failed()]);
+    }
+
+    #[test]
+    fn test_webhook_retries() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", server.server_addr().to_ip().unwrap());
+
+        let receiver = thread::spawn(move || {
+            let mut received = vec![];
+            for status in [503, 200, 400] {
+                let mut request = server.recv().unwrap();
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body).unwrap();
+                let token = request
+                    .headers()
+                    .iter()
+                    .find(|h| h.field.equiv("X-Token"))
+                    .map(|h| h.value.to_string());
+                received.push((token, body));
+                request.respond(tiny_http::Response::empty(status)).unwrap();
+            }
+            received
+        });
+
+        let mut sink = WebhookSink::new(WebhookConfig {
+            url,
+            headers: BTreeMap::from([("X-Token".to_string(), "s3cret".to_string())]),
+            body_template: None,
+            retries: 3,
+            events: vec![],
+            daily_summary: false,
+        });
+        sink.retry_delay = Duration::from_millis(10);
+
+        // 503 is retried, then succeeds
+        sink.send(&failed()).unwrap();
+        // 400 is given up immediately
+        assert!(sink.send(&failed()).unwrap_err().starts_with("[400]"));
+
+        let received = receiver.join().unwrap();
+        assert_eq!(received.len(), 3);
+        for (token, body) in received {
+            assert_eq!(token.as_deref(), Some("s3cret"));
+            let body: Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(body["event"], "punch_failed");
+            assert_eq!(body["punch_type"], "PunchOut");
+        }
+    }
+
+    /// Minimal SMTP server accepting one mail, returns the commands and the DATA it received.
+    fn spawn_smtp_stand_in() -> (u16, JoinHandle<(Vec<String>, String)>) {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut commands = vec![];
+            let mut data = String::new();
+
+            writer.write_all(b"220 localhost ESMTP\r\n").unwrap();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let command = line.trim_end().to_string();
+                let reply: &[u8] = match command.split(' ').next().unwrap() {
+                    "EHLO" => b"250 localhost\r\n",
+                    "DATA" => {
+                        writer.write_all(b"354 go ahead\r\n").unwrap();
+                        loop {
+                            let mut line = String::new();
+                            reader.read_line(&mut line).unwrap();
+                            if line == ".\r\n" {
+                                break;
+                            }
+                            data.push_str(&line);
+                        }
+                        b"250 queued\r\n"
+                    }
+                    "QUIT" => {
+                        writer.write_all(b"221 bye\r\n").unwrap();
+                        commands.push(command);
+                        break;
+                    }
+                    _ => b"250 ok\r\n",
+                };
+                commands.push(command);
+                writer.write_all(reply).unwrap();
+            }
+            (commands, data)
+        });
+
+        (port, handle)
+    }
+
+    fn email_config(port: u16) -> EmailConfig {
+        EmailConfig {
+            host: "127.0.0.1".to_string(),
+            port: Some(port),
+            security: SmtpSecurity::None,
+            username: None,
+            password: None,
+            from: "Apollo <apollo@example.com>".to_string(),
+            to: vec!["me@example.com".to_string(), "boss@example.com".to_string()],
+            events: vec![],
+            daily_summary: false,
+        }
+    }
+
+    #[test]
+    fn test_email_sink() {
+        let (port, smtp) = spawn_smtp_stand_in();
+
+        let sink = EmailSink::new(email_config(port)).unwrap();
+        sink.send(&Notification::PunchFailed {
+            punch_type: PunchType::PunchOut,
+            at: Local.with_ymd_and_hms(2023, 9, 23, 18, 5, 0).unwrap(),
+            error: "[400][Failed] {\"error\":\"punch rejected\"}".to_string(),
+        })
+        .unwrap();
+        drop(sink);
+
+        let (commands, data) = smtp.join().unwrap();
+        assert!(commands.contains(&"MAIL FROM:<apollo@example.com>".to_string()));
+        assert!(commands.contains(&"RCPT TO:<me@example.com>".to_string()));
+        assert!(commands.contains(&"RCPT TO:<boss@example.com>".to_string()));
+        assert!(data.contains("Subject: [apollo] PunchOut failed"));
+        // the server's error body is kept, long lines are soft wrapped by quoted-printable
+        assert!(data
+            .replace("=\r\n", "")
+            .contains("[400][Failed] {\"error\":\"punch rejected\"}"));
+    }
+
+    #[test]
+    fn test_email_wants() {
+        let mut config = email_config(25);
+        assert!(!EmailSink::new(config.clone())
+            .unwrap()
+            .wants(NotificationKind::DayPlanned));
+
+        config.daily_summary = true;
+        config.events = vec![NotificationKind::PunchFailed];
+        let sink = EmailSink::new(config.clone()).unwrap();
+        assert!(sink.wants(NotificationKind::DayPlanned));
+        assert!(sink.wants(NotificationKind::PunchFailed));
+        assert!(!sink.wants(NotificationKind::PunchSucceeded));
+
+        config.to = vec!["not an address".to_string()];
+        assert!(EmailSink::new(config).is_err());
+
+        // webhooks and telegram opt in the same way
+        assert!(!wants_event(&[], false, NotificationKind::DayPlanned));
+        assert!(wants_event(&[], false, NotificationKind::LoginFailed));
+        assert!(wants_event(&[], true, NotificationKind::DayPlanned));
+    }
+}
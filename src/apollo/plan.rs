@@ -0,0 +1,366 @@
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+
+use chrono::{DateTime, Duration, Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::agent::PunchType;
+use super::workday_schedule::WorkdaySchedule;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum PunchStatus {
+    Pending,
+    Done {
+        at: DateTime<Local>,
+        response: Value,
+    },
+    Failed {
+        at: DateTime<Local>,
+        error: String,
+    },
+    Skipped {
+        at: DateTime<Local>,
+        reason: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlannedPunch {
+    pub punch_type: PunchType,
+    /// shift time the punch was arranged from, used to detect schedule changes
+    pub shift_time: DateTime<Local>,
+    /// arranged punch time, shift time with jitter applied
+    pub planned_time: DateTime<Local>,
+    #[serde(flatten)]
+    pub status: PunchStatus,
+    /// when the punch postponed for lack of presence is given up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence_deadline: Option<DateTime<Local>>,
+}
+
+impl PlannedPunch {
+    fn arrange(schedule: &WorkdaySchedule, punch_type: PunchType, jitter: u32) -> Option<Self> {
+        schedule
+            .get_shift_time(punch_type)
+            .map(|shift_time| PlannedPunch {
+                punch_type,
+                shift_time,
+                planned_time: schedule.get_punch_time_with_jitter(punch_type, Some(jitter)),
+                status: PunchStatus::Pending,
+                presence_deadline: None,
+            })
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.status == PunchStatus::Pending
+    }
+}
+
+/// The punches arranged for one day and what happened to them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DayPlan {
+    pub date: NaiveDate,
+    pub punches: Vec<PlannedPunch>,
+    /// whether the day planned notification has been sent
+    #[serde(default)]
+    pub summary_sent: bool,
+}
+
+impl DayPlan {
+    /// Arranges the punches of `schedule` up to `jitter` seconds off the shift times.
+    pub fn new(schedule: &WorkdaySchedule, jitter: u32) -> Self {
+        DayPlan {
+            date: schedule.get_naive_date(),
+            punches: [PunchType::PunchIn, PunchType::PunchOut]
+                .into_iter()
+                .filter_map(|t| PlannedPunch::arrange(schedule, t, jitter))
+                .collect(),
+            summary_sent: false,
+        }
+    }
+
+    /// Re-arranges pending punches whose shift time differs from `schedule`,
+    /// punches already handled are kept as is. Returns true if anything changed.
+    pub fn update(&mut self, schedule: &WorkdaySchedule, jitter: u32) -> bool {
+        let mut changed = false;
+
+        self.punches.retain(|p| {
+            let keep = !p.is_pending() || schedule.get_shift_time(p.punch_type).is_some();
+            changed |= !keep;
+            keep
+        });
+
+        for punch_type in [PunchType::PunchIn, PunchType::PunchOut] {
+            let shift_time = match schedule.get_shift_time(punch_type) {
+                Some(v) => v,
+                None => continue,
+            };
+
+            match self.punches.iter_mut().find(|p| p.punch_type == punch_type) {
+                Some(p) if p.is_pending() && p.shift_time != shift_time => {
+                    *p = PlannedPunch::arrange(schedule, punch_type, jitter).unwrap();
+                    changed = true;
+                }
+                Some(_) => {}
+                None => {
+                    self.punches
+                        .push(PlannedPunch::arrange(schedule, punch_type, jitter).unwrap());
+                    self.punches.sort_by_key(|p| p.punch_type as u8);
+                    changed = true;
+                }
+            }
+        }
+
+        changed
+    }
+
+    /// Postpones a pending punch by `duration`.
+    pub fn delay(
+        &mut self,
+        punch_type: PunchType,
+        duration: Duration,
+    ) -> Result<&PlannedPunch, String> {
+        let p = self
+            .punches
+            .iter_mut()
+            .find(|p| p.punch_type == punch_type)
+            .ok_or_else(|| format!("no {} planned on {}", punch_type, self.date))?;
+
+        if !p.is_pending() {
+            return Err(format!(
+                "{} on {} is no longer pending",
+                punch_type, self.date
+            ));
+        }
+
+        p.planned_time += duration;
+        Ok(p)
+    }
+
+    /// Marks every pending punch as skipped.
+    pub fn skip_pending(&mut self, reason: &str) {
+        for p in self.punches.iter_mut().filter(|p| p.is_pending()) {
+            p.status = PunchStatus::Skipped {
+                at: Local::now(),
+                reason: reason.to_string(),
+            };
+        }
+    }
+
+    /// Forgets every pending punch, so the next `update` arranges them again.
+    pub fn drop_pending(&mut self) {
+        self.punches.retain(|p| !p.is_pending());
+    }
+
+    pub fn set_status(&mut self, punch_type: PunchType, status: PunchStatus) {
+        if let Some(p) = self.punches.iter_mut().find(|p| p.punch_type == punch_type) {
+            p.status = status;
+        }
+    }
+
+    pub fn next_pending(&self) -> Option<&PlannedPunch> {
+        self.punches.iter().find(|p| p.is_pending())
+    }
+
+    /// Pending punches whose planned time has already passed at `now`.
+    pub fn missed(&self, now: &DateTime<Local>) -> Vec<&PlannedPunch> {
+        self.punches
+            .iter()
+            .filter(|p| p.is_pending() && p.planned_time <= *now)
+            .collect()
+    }
+
+    /// Loads the plan saved in `path`, returns None if there is no state file yet.
+    pub fn load(path: &str) -> Result<Option<DayPlan>, String> {
+        let file = match File::open(path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("can't open state file {}\nreason: {}", path, e)),
+        };
+
+        serde_json::from_reader(file)
+            .map(Some)
+            .map_err(|e| format!("can't parse state file {}\nreason: {}", path, e))
+    }
+
+    /// Writes the plan into `path` atomically.
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let tmp_path = format!("{}.tmp", path);
+
+        File::create(&tmp_path)
+            .and_then(|mut file| {
+                file.write_all(serde_json::to_string_pretty(self).unwrap().as_bytes())?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp_path, path))
+            .map_err(|e| format!("can't write state file {}\nreason: {}", path, e))
+    }
+}
+
+impl std::fmt::Display for DayPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Auto punch plan of {}:", self.date)?;
+        for p in &self.punches {
+            let status = match &p.status {
+                PunchStatus::Pending => "pending".to_string(),
+                PunchStatus::Done { at, .. } => format!("done at {}", at),
+                PunchStatus::Failed { at, error } => format!("failed at {}: {}", at, error),
+                PunchStatus::Skipped { reason, .. } => format!("skipped, {}", reason),
+            };
+            write!(f, "\n    {}: {} ({})", p.punch_type, p.planned_time, status)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn get(plan: &DayPlan, punch_type: PunchType) -> Option<&PlannedPunch> {
+        plan.punches.iter().find(|p| p.punch_type == punch_type)
+    }
+
+    fn schedule(work_on: &str, work_off: &str) -> WorkdaySchedule {
+        WorkdaySchedule::from_json(&json!({
+            "Date": "2023-09-23T00:00:00+00:00",
+            "ShiftSchedule": {"WorkOnTime": work_on, "WorkOffTime": work_off}
+        }))
+    }
+
+    #[test]
+    fn test_update_keeps_handled_punches() {
+        let mut plan = DayPlan::new(
+            &schedule("2023-09-23T01:00:00+00:00", "2023-09-23T10:00:00+00:00"),
+            60,
+        );
+        plan.summary_sent = true;
+        let punch_in = get(&plan, PunchType::PunchIn).unwrap().clone();
+        plan.set_status(
+            PunchType::PunchIn,
+            PunchStatus::Done {
+                at: punch_in.planned_time,
+                response: json!({}),
+            },
+        );
+
+        // same schedule, nothing re-arranged
+        let before = plan.clone();
+        assert!(!plan.update(
+            &schedule("2023-09-23T01:00:00+00:00", "2023-09-23T10:00:00+00:00",),
+            60
+        ));
+        assert_eq!(plan, before);
+
+        // shift changed, only the pending punch out is re-arranged
+        assert!(plan.update(
+            &schedule("2023-09-23T02:00:00+00:00", "2023-09-23T11:00:00+00:00",),
+            60
+        ));
+        assert_eq!(
+            get(&plan, PunchType::PunchIn),
+            get(&before, PunchType::PunchIn)
+        );
+        assert_eq!(
+            get(&plan, PunchType::PunchOut).unwrap().shift_time,
+            DateTime::parse_from_rfc3339("2023-09-23T11:00:00+00:00").unwrap()
+        );
+        assert_eq!(plan.next_pending().unwrap().punch_type, PunchType::PunchOut);
+    }
+
+    #[test]
+    fn test_delay_and_skip() {
+        let mut plan = DayPlan::new(
+            &schedule("2023-09-23T01:00:00+00:00", "2023-09-23T10:00:00+00:00"),
+            60,
+        );
+        let punch_out = get(&plan, PunchType::PunchOut).unwrap().planned_time;
+
+        let delayed = plan
+            .delay(PunchType::PunchOut, Duration::minutes(90))
+            .unwrap();
+        assert_eq!(delayed.planned_time, punch_out + Duration::minutes(90));
+
+        plan.skip_pending("skipped by ctl");
+        assert!(plan.next_pending().is_none());
+        assert!(plan
+            .delay(PunchType::PunchOut, Duration::minutes(90))
+            .is_err());
+
+        // skipped punches are kept when re-arranging
+        let before = plan.clone();
+        plan.drop_pending();
+        assert!(!plan.update(
+            &schedule("2023-09-23T01:00:00+00:00", "2023-09-23T10:00:00+00:00",),
+            60
+        ));
+        assert_eq!(plan, before);
+    }
+
+    #[test]
+    fn test_missed() {
+        let plan = DayPlan::new(
+            &schedule("2023-09-23T01:00:00+00:00", "2023-09-23T10:00:00+00:00"),
+            60,
+        );
+        let noon = DateTime::parse_from_rfc3339("2023-09-23T04:00:00+00:00")
+            .unwrap()
+            .with_timezone(&Local);
+
+        let missed = plan.missed(&noon);
+        assert_eq!(missed.len(), 1);
+        assert_eq!(missed[0].punch_type, PunchType::PunchIn);
+    }
+
+    #[test]
+    fn test_jitter() {
+        let plan = DayPlan::new(
+            &schedule("2023-09-23T01:00:00+00:00", "2023-09-23T10:00:00+00:00"),
+            0,
+        );
+        assert!(plan.punches.iter().all(|p| p.planned_time == p.shift_time));
+
+        let plan = DayPlan::new(
+            &schedule("2023-09-23T01:00:00+00:00", "2023-09-23T10:00:00+00:00"),
+            30,
+        );
+        let punch_in = get(&plan, PunchType::PunchIn).unwrap();
+        assert!(punch_in.planned_time < punch_in.shift_time);
+        assert!(punch_in.shift_time - punch_in.planned_time <= Duration::seconds(30));
+        let punch_out = get(&plan, PunchType::PunchOut).unwrap();
+        assert!(punch_out.planned_time > punch_out.shift_time);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir()
+            .join(format!(
+                "apollo-plan-test-{}.state.json",
+                std::process::id()
+            ))
+            .to_string_lossy()
+            .to_string();
+
+        assert_eq!(DayPlan::load(&path).unwrap(), None);
+
+        let mut plan = DayPlan::new(
+            &schedule("2023-09-23T01:00:00+00:00", "2023-09-23T10:00:00+00:00"),
+            60,
+        );
+        plan.set_status(
+            PunchType::PunchIn,
+            PunchStatus::Failed {
+                at: Local::now(),
+                error: "[500][Failed] {}".to_string(),
+            },
+        );
+        plan.punches[1].presence_deadline = Some(plan.punches[1].planned_time);
+        plan.save(&path).unwrap();
+
+        assert_eq!(DayPlan::load(&path).unwrap(), Some(plan));
+        fs::remove_file(&path).unwrap();
+    }
+}
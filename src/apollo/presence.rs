@@ -0,0 +1,165 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+
+use super::agent::PunchType;
+use super::config::PresenceCheck;
+use super::hooks::{run_hook, HookContext};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Runs `check`, an error tells why the user is considered absent.
+pub fn check_presence(
+    check: &PresenceCheck,
+    punch_type: PunchType,
+    scheduled_time: DateTime<Local>,
+    dry_run: bool,
+) -> Result<(), String> {
+    match check {
+        PresenceCheck::Reachable { address } => check_reachable(address),
+        PresenceCheck::Subnet { cidr } => check_subnet(cidr),
+        PresenceCheck::Command { command } => run_hook(
+            command,
+            &HookContext {
+                hook: "presence",
+                punch_type,
+                scheduled_time: Some(scheduled_time),
+                actual_time: None,
+                response: None,
+                error: None,
+                dry_run,
+            },
+            COMMAND_TIMEOUT,
+        ),
+    }
+}
+
+fn check_reachable(address: &str) -> Result<(), String> {
+    let addrs: Vec<SocketAddr> = address
+        .to_socket_addrs()
+        .map_err(|e| format!("can't resolve {}: {}", address, e))?
+        .collect();
+
+    let mut error = format!("{} has no address", address);
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(_) => return Ok(()),
+            Err(e) => error = format!("{} unreachable: {}", address, e),
+        }
+    }
+    Err(error)
+}
+
+/// Parses "192.168.1.0/24" or "fd00::/8".
+pub fn parse_cidr(cidr: &str) -> Result<(IpAddr, u32), String> {
+    let invalid = || {
+        format!(
+            "invalid subnet {}, expect something like 192.168.1.0/24",
+            cidr
+        )
+    };
+
+    let (addr, prefix) = cidr.split_once('/').ok_or_else(invalid)?;
+    let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+    let prefix: u32 = prefix.parse().map_err(|_| invalid())?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    if prefix > max {
+        return Err(invalid());
+    }
+
+    Ok((addr, prefix))
+}
+
+fn in_subnet(ip: IpAddr, network: IpAddr, prefix: u32) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+fn check_subnet(cidr: &str) -> Result<(), String> {
+    let (network, prefix) = parse_cidr(cidr)?;
+
+    // connecting a UDP socket sends nothing, it only makes the kernel pick the
+    // source address of the route towards the subnet
+    let unspecified: IpAddr = match network {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let local = UdpSocket::bind((unspecified, 0))
+        .and_then(|socket| {
+            socket.connect((network, 9))?;
+            socket.local_addr()
+        })
+        .map_err(|e| format!("no route to {}: {}", cidr, e))?
+        .ip();
+
+    if in_subnet(local, network, prefix) {
+        Ok(())
+    } else {
+        Err(format!("this machine is at {}, outside of {}", local, cidr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_subnet() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        assert_eq!(parse_cidr("192.168.1.0/24"), Ok((ip("192.168.1.0"), 24)));
+        assert!(parse_cidr("192.168.1.0/33").is_err());
+        assert!(parse_cidr("192.168.1.0").is_err());
+
+        assert!(in_subnet(ip("192.168.1.77"), ip("192.168.1.0"), 24));
+        assert!(!in_subnet(ip("192.168.2.77"), ip("192.168.1.0"), 24));
+        assert!(in_subnet(ip("10.1.2.3"), ip("0.0.0.0"), 0));
+        assert!(in_subnet(ip("fd00::1"), ip("fd00::"), 8));
+        assert!(!in_subnet(ip("fd00::1"), ip("10.0.0.0"), 8));
+
+        assert!(check_subnet("127.0.0.0/8").is_ok());
+    }
+
+    #[test]
+    fn test_check_presence() {
+        let scheduled = Local::now();
+        let check = |c: PresenceCheck| check_presence(&c, PunchType::PunchIn, scheduled, false);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        assert!(check(PresenceCheck::Reachable {
+            address: address.clone()
+        })
+        .is_ok());
+        drop(listener);
+        assert!(check(PresenceCheck::Reachable { address }).is_err());
+
+        assert!(check(PresenceCheck::Command {
+            command: r#"test "$APOLLO_HOOK" = presence"#.to_string()
+        })
+        .is_ok());
+        assert!(check(PresenceCheck::Command {
+            command: "exit 1".to_string()
+        })
+        .is_err());
+        let dry_run = PresenceCheck::Command {
+            command: r#"test "$APOLLO_DRY_RUN" = true"#.to_string(),
+        };
+        assert!(check_presence(&dry_run, PunchType::PunchIn, scheduled, true).is_ok());
+        assert!(check_presence(&dry_run, PunchType::PunchIn, scheduled, false).is_err());
+    }
+}
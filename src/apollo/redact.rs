@@ -0,0 +1,194 @@
+use serde_json::Value;
+
+const REDACTED: &str = "<redacted>";
+
+/// Header names whose values are never logged.
+const SECRET_HEADERS: &[&str] = &["authorization", "cookie", "set-cookie"];
+
+/// Form, query and JSON keys whose values are never logged.
+const SECRET_KEYS: &[&str] = &[
+    "password",
+    "__requestverificationtoken",
+    "code",
+    "access_token",
+    "refresh_token",
+    "token",
+];
+
+fn is_secret_key(key: &str) -> bool {
+    SECRET_KEYS.contains(&key.to_ascii_lowercase().as_str())
+}
+
+/// Scrubs credentials out of HTTP traffic before it gets logged.
+pub struct Redactor {
+    /// literal values (e.g. the password) removed wherever they show up
+    secrets: Vec<String>,
+}
+
+impl Redactor {
+    pub fn new(secrets: Vec<String>) -> Self {
+        Redactor {
+            secrets: secrets.into_iter().filter(|v| !v.is_empty()).collect(),
+        }
+    }
+
+    pub fn header(&self, name: &str, value: &str) -> String {
+        if SECRET_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+            REDACTED.to_string()
+        } else {
+            self.text(value)
+        }
+    }
+
+    pub fn url(&self, url: &str) -> String {
+        match url.split_once('?') {
+            Some((path, query)) => format!("{}?{}", path, self.form(query)),
+            None => self.text(url),
+        }
+    }
+
+    /// `application/x-www-form-urlencoded` bodies and query strings.
+    pub fn form(&self, form: &str) -> String {
+        form.split('&')
+            .map(|pair| match pair.split_once('=') {
+                Some((key, _)) if is_secret_key(key) => format!("{}={}", key, REDACTED),
+                _ => self.text(pair),
+            })
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+
+    /// Any body, redacting by key when it is JSON or a form.
+    pub fn body(&self, body: &str) -> String {
+        if let Ok(mut json) = serde_json::from_str::<Value>(body) {
+            redact_json(&mut json);
+            return self.text(&json.to_string());
+        }
+
+        if !body.contains(char::is_whitespace) && body.contains('=') {
+            return self.form(body);
+        }
+
+        self.text(&redact_html_token(body))
+    }
+
+    /// Free text, removes the known secrets and bearer tokens.
+    pub fn text(&self, text: &str) -> String {
+        let mut text = self.secrets.iter().fold(text.to_string(), |acc, secret| {
+            acc.replace(secret, REDACTED)
+        });
+
+        let mut from = 0;
+        while let Some(i) = text[from..].find("Bearer ") {
+            let start = from + i + "Bearer ".len();
+            let end = text[start..]
+                .find(|c: char| c.is_whitespace() || c == '"' || c == '\'')
+                .map_or(text.len(), |v| start + v);
+            text.replace_range(start..end, REDACTED);
+            from = start + REDACTED.len();
+        }
+
+        text
+    }
+}
+
+/// Replaces the values of secret keys, e.g. before a config is printed.
+pub fn redact_json(json: &mut Value) {
+    match json {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                // objects are kept, e.g. a config's {"password": {"env": ...}}
+                if is_secret_key(key) && !value.is_null() && !value.is_object() {
+                    *value = Value::String(REDACTED.to_string());
+                } else {
+                    redact_json(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact_json),
+        _ => {}
+    }
+}
+
+/// Blanks the value of the `__RequestVerificationToken` inputs of the login page.
+fn redact_html_token(html: &str) -> String {
+    let mut html = html.to_string();
+    let mut from = 0;
+
+    while let Some(i) = html[from..].find("__RequestVerificationToken") {
+        let tag_start = from + i;
+        let tag_end = html[tag_start..]
+            .find('>')
+            .map_or(html.len(), |v| tag_start + v);
+
+        match html[tag_start..tag_end].find("value=\"") {
+            Some(v) => {
+                let start = tag_start + v + "value=\"".len();
+                let end = html[start..].find('"').map_or(html.len(), |v| start + v);
+                html.replace_range(start..end, REDACTED);
+                from = start + REDACTED.len();
+            }
+            None => from = tag_end,
+        }
+    }
+
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redactor() -> Redactor {
+        Redactor::new(vec!["hunter2".to_string()])
+    }
+
+    #[test]
+    fn test_headers() {
+        let r = redactor();
+        assert_eq!(r.header("Cookie", "session=abc"), REDACTED);
+        assert_eq!(r.header("set-cookie", "session=abc"), REDACTED);
+        assert_eq!(r.header("Authorization", "Bearer abc"), REDACTED);
+        assert_eq!(r.header("Functioncode", "PunchCard"), "PunchCard");
+    }
+
+    #[test]
+    fn test_url_and_form() {
+        let r = redactor();
+        assert_eq!(
+            r.url("https://linkup-be.mayohr.com/api/auth/checkticket?code=abc&x=1"),
+            "https://linkup-be.mayohr.com/api/auth/checkticket?code=<redacted>&x=1"
+        );
+        assert_eq!(
+            r.body("__RequestVerificationToken=tok&companyCode=ACME&password=hunter2&userName=ACME-1"),
+            "__RequestVerificationToken=<redacted>&companyCode=ACME&password=<redacted>&userName=ACME-1"
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        let r = redactor();
+        assert_eq!(
+            r.body(
+                r#"{"code":"abc","Data":{"access_token":"xyz","Name":"me"},"note":"pw hunter2"}"#
+            ),
+            r#"{"code":"<redacted>","Data":{"access_token":"<redacted>","Name":"me"},"note":"pw <redacted>"}"#
+        );
+
+        let mut config = serde_json::json!({"password": {"env": "PW"}, "telegram": {"token": "t"}});
+        redact_json(&mut config);
+        assert_eq!(
+            config,
+            serde_json::json!({"password": {"env": "PW"}, "telegram": {"token": REDACTED}})
+        );
+    }
+
+    #[test]
+    fn test_text() {
+        let r = redactor();
+        assert_eq!(
+            r.body(r#"<input name="__RequestVerificationToken" type="hidden" value="tok123" /><p>Bearer abc.def ok</p>"#),
+            r#"<input name="__RequestVerificationToken" type="hidden" value="<redacted>" /><p>Bearer <redacted> ok</p>"#
+        );
+    }
+}
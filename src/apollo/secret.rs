@@ -0,0 +1,372 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::{Mutex, OnceLock};
+
+use age::secrecy::SecretString;
+use clap::ValueEnum;
+use tracing::warn;
+
+use super::config::{get_password_filename, PasswordSource};
+
+/// Keyring service `init --store keyring` saves passwords under.
+const KEYRING_SERVICE: &str = "apollo-hr-agent";
+
+/// Environment variable holding the passphrase of encrypted passwords.
+const PASSPHRASE_ENV: &str = "APOLLO_PASSPHRASE";
+
+/// Line read from --passphrase-fd, kept whether or not it decrypts, the fd is closed after.
+static FD_PASSPHRASE: OnceLock<Result<String, String>> = OnceLock::new();
+
+/// A terminal gives the passphrase once, but a daemon needs it again on reload.
+static PASSPHRASE: Mutex<Option<String>> = Mutex::new(None);
+
+/// Where `init` keeps the password.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum PasswordStore {
+    /// in the config file
+    Plain,
+    /// in a file next to the config, readable by its owner only
+    File,
+    /// in the Linux Secret Service, e.g. GNOME Keyring or KWallet
+    Keyring,
+}
+
+/// Keyring entries are per employee, a service may hold several of them.
+fn keyring_entry(service: &str, username: &str, company: &str) -> Result<keyring::Entry, String> {
+    keyring::Entry::new(service, &format!("{}@{}", username, company))
+        .map_err(|e| format!("can't open keyring entry of {}: {}", service, e))
+}
+
+/// Fetches the password `source` points to.
+pub fn resolve_password(
+    source: &PasswordSource,
+    username: &str,
+    company: &str,
+) -> Result<String, String> {
+    let password = match source {
+        PasswordSource::Plain(password) => password.clone(),
+        PasswordSource::Env { env } => std::env::var(env)
+            .map_err(|e| format!("can't read the password from ${}: {}", env, e))?,
+        PasswordSource::Command { command } => {
+            // stdin and stderr stay attached, so e.g. gpg can ask for its passphrase and its
+            // errors are seen, `output` would close stdin and capture stderr otherwise
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .stdin(Stdio::inherit())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::inherit())
+                .output()
+                .map_err(|e| format!("can't run password command {}: {}", command, e))?;
+            if !output.status.success() {
+                return Err(format!(
+                    "password command {} exited with {}",
+                    command, output.status
+                ));
+            }
+            // like pass, the password is the first line, the rest may be notes
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        }
+        PasswordSource::File { file } => {
+            let metadata = fs::metadata(file)
+                .map_err(|e| format!("can't read the password file {}: {}", file, e))?;
+            if metadata.permissions().mode() & 0o077 != 0 {
+                warn!("password file {} is readable by others, chmod 600 it", file);
+            }
+            fs::read_to_string(file)
+                .map_err(|e| format!("can't read the password file {}: {}", file, e))?
+                .trim_end_matches(['\r', '\n'])
+                .to_string()
+        }
+        PasswordSource::Keyring { keyring } => keyring_entry(keyring, username, company)?
+            .get_password()
+            .map_err(|e| format!("can't read the password from keyring {}: {}", keyring, e))?,
+        PasswordSource::Encrypted { encrypted } => {
+            with_passphrase(false, |passphrase| decrypt(encrypted, passphrase))?
+        }
+    };
+
+    if password.is_empty() {
+        return Err("the password is empty".to_string());
+    }
+    Ok(password)
+}
+
+/// Takes the passphrase read by `set_passphrase_fd`, $APOLLO_PASSPHRASE or the terminal,
+/// in that order. It is cached once `f` accepts it.
+pub fn with_passphrase<T, F>(confirm: bool, f: F) -> Result<T, String>
+where
+    F: FnOnce(&str) -> Result<T, String>,
+{
+    // held while prompting, so concurrent accounts ask only once
+    let mut cached = PASSPHRASE.lock().unwrap();
+    if let Some(passphrase) = cached.as_ref() {
+        return f(passphrase);
+    }
+
+    let passphrase = if let Some(passphrase) = FD_PASSPHRASE.get() {
+        passphrase.clone()?
+    } else if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        passphrase
+    } else {
+        let prompt = |prompt: &str| {
+            rpassword::prompt_password(prompt).map_err(|e| {
+                format!(
+                    "can't prompt for the passphrase, pass it with --passphrase-fd or ${}: {}",
+                    PASSPHRASE_ENV, e
+                )
+            })
+        };
+        let passphrase = prompt("passphrase: ")?;
+        if confirm && prompt("passphrase again: ")? != passphrase {
+            return Err("the passphrases don't match".to_string());
+        }
+        passphrase
+    };
+    if passphrase.is_empty() {
+        return Err("the passphrase is empty".to_string());
+    }
+
+    let result = f(&passphrase)?;
+    *cached = Some(passphrase);
+    Ok(result)
+}
+
+/// Reads the passphrase `with_passphrase` uses from `fd` right away. Only the first call
+/// reads, the fd is closed after and its number may be reused by anything opened later,
+/// so call it before opening anything else, `fd` can only be told from our own files then.
+pub fn set_passphrase_fd(fd: RawFd) -> Result<(), String> {
+    // closing stdin, stdout or stderr after the read would break whatever uses them later
+    if fd <= 2 {
+        return Err(format!(
+            "--passphrase-fd {} is a standard stream, pass another fd, e.g. 3<passphrase.txt",
+            fd
+        ));
+    }
+    // SAFETY: F_GETFD only reads the flags of fd, an invalid fd fails with EBADF
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(format!(
+            "--passphrase-fd {} is not an open file descriptor: {}",
+            fd,
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    FD_PASSPHRASE.get_or_init(|| {
+        // SAFETY: the fd is open and was handed over on the command line for this only,
+        // and the OnceLock makes sure it is taken over, read and closed once
+        let file = unsafe { File::from_raw_fd(fd) };
+        let mut line = String::new();
+        BufReader::new(file)
+            .read_line(&mut line)
+            .map_err(|e| format!("can't read the passphrase from fd {}: {}", fd, e))?;
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    });
+    Ok(())
+}
+
+/// Encrypts `password` into an armored age file, with the default scrypt work factor
+/// unless `work_factor` is given.
+fn encrypt(password: &str, passphrase: &str, work_factor: Option<u8>) -> Result<String, String> {
+    let mut recipient = age::scrypt::Recipient::new(SecretString::from(passphrase));
+    if let Some(log_n) = work_factor {
+        recipient.set_work_factor(log_n);
+    }
+
+    age::encrypt_and_armor(&recipient, password.as_bytes())
+        .map_err(|e| format!("can't encrypt the password: {}", e))
+}
+
+fn decrypt(encrypted: &str, passphrase: &str) -> Result<String, String> {
+    let identity = age::scrypt::Identity::new(SecretString::from(passphrase));
+    let password = age::decrypt(&identity, encrypted.as_bytes())
+        .map_err(|e| format!("can't decrypt the password: {}", e))?;
+
+    String::from_utf8(password).map_err(|_| "the decrypted password isn't UTF-8".to_string())
+}
+
+/// `config encrypt` of one password, plain ones only.
+pub fn encrypt_password(
+    source: &PasswordSource,
+    passphrase: &str,
+) -> Result<Option<PasswordSource>, String> {
+    match source {
+        PasswordSource::Plain(password) => Ok(Some(PasswordSource::Encrypted {
+            encrypted: encrypt(password, passphrase, None)?,
+        })),
+        _ => Ok(None),
+    }
+}
+
+/// `config decrypt` of one password, encrypted ones only.
+pub fn decrypt_password(
+    source: &PasswordSource,
+    passphrase: &str,
+) -> Result<Option<PasswordSource>, String> {
+    match source {
+        PasswordSource::Encrypted { encrypted } => {
+            Ok(Some(PasswordSource::Plain(decrypt(encrypted, passphrase)?)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Keeps `password` in `store`, returns what the config should hold.
+pub fn store_password(
+    store: PasswordStore,
+    config_name: &String,
+    password: &str,
+    username: &str,
+    company: &str,
+) -> Result<PasswordSource, String> {
+    match store {
+        PasswordStore::Plain => Ok(PasswordSource::Plain(password.to_string())),
+        PasswordStore::File => store_password_file(&get_password_filename(config_name), password),
+        PasswordStore::Keyring => store_password_keyring(password, username, company),
+    }
+}
+
+/// Writes `password` into a file only its owner can read.
+fn store_password_file(filename: &str, password: &str) -> Result<PasswordSource, String> {
+    // init stores the password before it writes the config, which creates the directory
+    if let Some(dir) = Path::new(filename).parent() {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("can't create the directory {}: {}", dir.display(), e))?;
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(filename)
+        .map_err(|e| format!("can't create the password file {}: {}", filename, e))?;
+    // the mode only applies to new files
+    file.set_permissions(fs::Permissions::from_mode(0o600))
+        .and_then(|_| file.write_all(password.as_bytes()))
+        .map_err(|e| format!("can't write the password file {}: {}", filename, e))?;
+
+    // daemons may run from another directory
+    let file = fs::canonicalize(filename)
+        .map_err(|e| format!("can't locate the password file {}: {}", filename, e))?;
+    Ok(PasswordSource::File {
+        file: file.to_string_lossy().to_string(),
+    })
+}
+
+/// Saves `password` into the Secret Service under `KEYRING_SERVICE`.
+fn store_password_keyring(
+    password: &str,
+    username: &str,
+    company: &str,
+) -> Result<PasswordSource, String> {
+    keyring_entry(KEYRING_SERVICE, username, company)?
+        .set_password(password)
+        .map_err(|e| format!("can't save the password into the keyring: {}", e))?;
+
+    Ok(PasswordSource::Keyring {
+        keyring: KEYRING_SERVICE.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+
+    use super::*;
+
+    #[test]
+    fn test_resolve_password() {
+        let resolve = |source: PasswordSource| resolve_password(&source, "1234", "ACME");
+
+        assert_eq!(
+            resolve(PasswordSource::Plain("hunter2".to_string())),
+            Ok("hunter2".to_string())
+        );
+        assert!(resolve(PasswordSource::Plain("".to_string())).is_err());
+
+        assert_eq!(
+            resolve(PasswordSource::Command {
+                command: "printf 'hunter2\\nurl: mayohr.com\\n'".to_string()
+            }),
+            Ok("hunter2".to_string())
+        );
+        assert!(resolve(PasswordSource::Command {
+            command: "echo hunter2; exit 1".to_string()
+        })
+        .is_err());
+        // diagnostics on stderr are not taken for the password
+        assert_eq!(
+            resolve(PasswordSource::Command {
+                command: "echo 'gpg: decrypting' >&2; echo hunter2".to_string()
+            }),
+            Ok("hunter2".to_string())
+        );
+
+        assert!(resolve(PasswordSource::Env {
+            env: "APOLLO_TEST_UNSET_PASSWORD".to_string()
+        })
+        .is_err());
+
+        // a fresh config directory, not created yet
+        let dir = std::env::temp_dir().join(format!("apollo-password-test-{}", std::process::id()));
+        let filename = dir.join("apollo.password").to_string_lossy().to_string();
+        let source = store_password_file(&filename, "hunter2\n").unwrap();
+        assert_eq!(
+            fs::metadata(&filename).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(resolve(source), Ok("hunter2".to_string()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        // the default work factor takes seconds, more so in debug builds
+        let encrypted = encrypt("hunter2", "correct horse", Some(10)).unwrap();
+        assert!(encrypted.starts_with("-----BEGIN AGE ENCRYPTED FILE-----"));
+        assert!(!encrypted.contains("hunter2"));
+
+        assert_eq!(
+            decrypt(&encrypted, "correct horse"),
+            Ok("hunter2".to_string())
+        );
+        assert!(decrypt(&encrypted, "wrong horse").is_err());
+
+        let source = PasswordSource::Encrypted { encrypted };
+        assert_eq!(
+            decrypt_password(&source, "correct horse"),
+            Ok(Some(PasswordSource::Plain("hunter2".to_string())))
+        );
+        assert_eq!(encrypt_password(&source, "correct horse"), Ok(None));
+    }
+
+    #[test]
+    fn test_passphrase_fd() {
+        let (mut writer, reader) = UnixStream::pair().unwrap();
+        writer.write_all(b"correct horse\n").unwrap();
+        drop(writer);
+
+        assert!(set_passphrase_fd(0).is_err());
+        assert!(set_passphrase_fd(2).is_err());
+        // far above anything the test opens
+        assert!(set_passphrase_fd(9999).is_err());
+
+        set_passphrase_fd(reader.into_raw_fd()).unwrap();
+
+        // a wrong guess neither caches it nor reads the fd again
+        assert!(with_passphrase(false, |_| Err::<(), _>("wrong".to_string())).is_err());
+        assert_eq!(
+            with_passphrase(false, |passphrase| Ok(passphrase.to_string())),
+            Ok("correct horse".to_string())
+        );
+    }
+}
@@ -0,0 +1,358 @@
+use std::io::Cursor;
+
+use chrono::{Datelike, Local, Months, NaiveDate};
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Request, Response};
+use tracing::{info, warn};
+
+use super::agent::{ApolloAgent, PunchType};
+use super::config::HooksConfig;
+use super::control::punch_via_daemon;
+use super::hooks::punch_with_hooks;
+use super::plan::{DayPlan, PunchStatus};
+
+/// Longest range `GET /calendar` accepts, every month in the range is one Mayo request.
+const MAX_CALENDAR_DAYS: i64 = 366;
+
+/// HTTP JSON API wrapping an `ApolloAgent`, requests are served one at a time.
+pub struct ApiServer {
+    agent: ApolloAgent,
+    hooks: HooksConfig,
+    token: String,
+    state_filename: String,
+    lock_filename: String,
+    socket_filename: String,
+}
+
+type ApiResult = Result<Value, (u16, String)>;
+
+fn parse_query(query: &str) -> Vec<(&str, &str)> {
+    query
+        .split('&')
+        .filter(|v| !v.is_empty())
+        .map(|v| v.split_once('=').unwrap_or((v, "")))
+        .collect()
+}
+
+fn parse_date(query: &[(&str, &str)], key: &str) -> Result<Option<NaiveDate>, (u16, String)> {
+    query
+        .iter()
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| {
+            NaiveDate::parse_from_str(v, "%Y-%m-%d")
+                .map_err(|e| (400, format!("invalid {} {}: {}", key, v, e)))
+        })
+        .transpose()
+}
+
+/// First day of every month between `from` and `to`.
+fn months_between(from: NaiveDate, to: NaiveDate) -> Vec<NaiveDate> {
+    let mut months = Vec::new();
+    let mut month = from.with_day(1).unwrap();
+
+    while month <= to {
+        months.push(month);
+        month = if month.month() == 12 {
+            NaiveDate::from_ymd_opt(month.year() + 1, 1, 1).unwrap()
+        } else {
+            NaiveDate::from_ymd_opt(month.year(), month.month() + 1, 1).unwrap()
+        };
+    }
+
+    months
+}
+
+/// Compares without bailing out early, so the token can't be guessed by timing.
+fn token_matches(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+impl ApiServer {
+    pub fn new(
+        agent: ApolloAgent,
+        hooks: &HooksConfig,
+        token: &str,
+        state_filename: &str,
+        lock_filename: &str,
+        socket_filename: &str,
+    ) -> Result<Self, String> {
+        // an empty token would match an empty bearer token
+        if token.trim().is_empty() {
+            return Err("the api token is empty".to_string());
+        }
+
+        Ok(ApiServer {
+            agent,
+            hooks: hooks.clone(),
+            token: token.to_string(),
+            state_filename: state_filename.to_string(),
+            lock_filename: lock_filename.to_string(),
+            socket_filename: socket_filename.to_string(),
+        })
+    }
+
+    pub fn serve(&mut self, listen: &str) -> Result<(), String> {
+        let server = tiny_http::Server::http(listen)
+            .map_err(|e| format!("can't listen on {}\nreason: {}", listen, e))?;
+        info!("api server listening on http://{}", listen);
+
+        for request in server.incoming_requests() {
+            self.respond(request);
+        }
+
+        Ok(())
+    }
+
+    fn respond(&mut self, request: Request) {
+        let authorization = request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Authorization"))
+            .map(|h| h.value.to_string());
+
+        let (status, body) = match self.handle(request.method(), request.url(), authorization) {
+            Ok(v) => (200, v),
+            Err((status, error)) => (status, json!({ "error": error })),
+        };
+        info!("{} {} {}", request.method(), request.url(), status);
+
+        let response = Response::new(
+            status.into(),
+            vec![Header::from_bytes("Content-Type", "application/json").unwrap()],
+            Cursor::new(body.to_string().into_bytes()),
+            None,
+            None,
+        );
+        if let Err(e) = request.respond(response) {
+            warn!("can't send response: {}", e);
+        }
+    }
+
+    fn handle(&mut self, method: &Method, url: &str, authorization: Option<String>) -> ApiResult {
+        let (path, query) = url.split_once('?').unwrap_or((url, ""));
+        let query = parse_query(query);
+
+        if (method, path) == (&Method::Get, "/health") {
+            return Ok(json!({ "status": "ok" }));
+        }
+
+        let authorized = authorization
+            .as_deref()
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .is_some_and(|v| token_matches(&self.token, v));
+        if !authorized {
+            return Err((401, "missing or invalid bearer token".to_string()));
+        }
+
+        match (method, path) {
+            (Method::Get, "/calendar") => self.get_calendar(&query),
+            (Method::Get, "/today") => self
+                .with_relogin(|agent| agent.get_today_schedule())
+                .map(|v| v.to_json()),
+            (Method::Post, "/punch/in") => self.punch(PunchType::PunchIn),
+            (Method::Post, "/punch/out") => self.punch(PunchType::PunchOut),
+            (Method::Get, "/plan") => self.get_plan(),
+            (_, "/calendar" | "/today" | "/punch/in" | "/punch/out" | "/plan") => {
+                Err((405, format!("{} not allowed on {}", method, path)))
+            }
+            _ => Err((404, format!("{} not found", path))),
+        }
+    }
+
+    /// Runs `f`, logs in again and retries once if it fails, the session may have expired.
+    fn with_relogin<T, F>(&mut self, f: F) -> Result<T, (u16, String)>
+    where
+        F: Fn(&ApolloAgent) -> Result<T, String>,
+    {
+        f(&self.agent)
+            .or_else(|_| {
+                self.agent.login()?;
+                f(&self.agent)
+            })
+            .map_err(|e| (502, e))
+    }
+
+    fn get_calendar(&mut self, query: &[(&str, &str)]) -> ApiResult {
+        let today = Local::now().date_naive();
+        let from = parse_date(query, "from")?.unwrap_or(today.with_day(1).unwrap());
+        // the whole month of `from` by default
+        let to = match parse_date(query, "to")? {
+            Some(v) => v,
+            None => (from.with_day(1).unwrap() + Months::new(1))
+                .pred_opt()
+                .unwrap(),
+        };
+
+        if to < from {
+            return Err((400, "to is earlier than from".to_string()));
+        }
+        if (to - from).num_days() > MAX_CALENDAR_DAYS {
+            return Err((400, format!("range longer than {} days", MAX_CALENDAR_DAYS)));
+        }
+
+        let mut schedules = Vec::new();
+        for month in months_between(from, to) {
+            schedules.extend(self.with_relogin(|agent| {
+                agent.get_workday_schedules(Some(month.year()), Some(month.month()))
+            })?);
+        }
+
+        Ok(Value::Array(
+            schedules
+                .iter()
+                .filter(|s| (from..=to).contains(&s.get_naive_date()))
+                .map(|s| s.to_json())
+                .collect(),
+        ))
+    }
+
+    fn punch(&mut self, punch_type: PunchType) -> ApiResult {
+        // a running daemon would punch its pending punch again later
+        let status = match punch_via_daemon(&self.lock_filename, &self.socket_filename, punch_type)
+            .map_err(|e| (502, e))?
+        {
+            Some(v) => v,
+            None => {
+                // the session left from startup may have expired, log in before the single attempt
+                self.agent.login().map_err(|e| (502, e))?;
+                // never retry a punch, a failed response may still have been recorded
+                punch_with_hooks(&self.agent, &self.hooks, punch_type, None)
+            }
+        };
+
+        match status {
+            PunchStatus::Done { response, .. } => {
+                Ok(json!({ "punch_type": punch_type, "response": response }))
+            }
+            PunchStatus::Failed { error, .. } => Err((502, error)),
+            PunchStatus::Skipped { reason, .. } => Err((409, reason)),
+            PunchStatus::Pending => unreachable!(),
+        }
+    }
+
+    fn get_plan(&self) -> ApiResult {
+        let today = Local::now().date_naive();
+
+        match DayPlan::load(&self.state_filename).map_err(|e| (500, e))? {
+            Some(plan) if plan.date == today => Ok(serde_json::to_value(plan).unwrap()),
+            _ => Err((404, format!("no auto punch plan for {}", today))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server() -> ApiServer {
+        ApiServer::new(
+            ApolloAgent::new("user", "password", "company"),
+            &HooksConfig::default(),
+            "secret",
+            "/nonexistent/apollo.state.json",
+            "/nonexistent/apollo.lock",
+            "/nonexistent/apollo.sock",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_auth() {
+        for token in ["", " "] {
+            assert!(ApiServer::new(
+                ApolloAgent::new("user", "password", "company"),
+                &HooksConfig::default(),
+                token,
+                "/nonexistent/apollo.state.json",
+                "/nonexistent/apollo.lock",
+                "/nonexistent/apollo.sock",
+            )
+            .is_err());
+        }
+
+        let mut server = server();
+
+        assert_eq!(
+            server.handle(&Method::Get, "/health", None),
+            Ok(json!({"status": "ok"}))
+        );
+        assert_eq!(
+            server.handle(&Method::Get, "/today", None).unwrap_err().0,
+            401
+        );
+        assert_eq!(
+            server
+                .handle(
+                    &Method::Post,
+                    "/punch/in",
+                    Some("Bearer wrong!".to_string())
+                )
+                .unwrap_err()
+                .0,
+            401
+        );
+        assert_eq!(
+            server
+                .handle(&Method::Get, "/nothing", Some("Bearer secret".to_string()))
+                .unwrap_err()
+                .0,
+            404
+        );
+        assert_eq!(
+            server
+                .handle(&Method::Get, "/punch/in", Some("Bearer secret".to_string()))
+                .unwrap_err()
+                .0,
+            405
+        );
+        assert_eq!(
+            server
+                .handle(&Method::Get, "/plan", Some("Bearer secret".to_string()))
+                .unwrap_err()
+                .0,
+            404
+        );
+        assert_eq!(
+            server
+                .handle(
+                    &Method::Get,
+                    "/calendar?from=2023-09-01&to=2023-08-01",
+                    Some("Bearer secret".to_string())
+                )
+                .unwrap_err()
+                .0,
+            400
+        );
+    }
+
+    #[test]
+    fn test_months_between() {
+        let d = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+
+        assert_eq!(
+            months_between(d(2023, 9, 15), d(2023, 9, 20)),
+            [d(2023, 9, 1)]
+        );
+        assert_eq!(
+            months_between(d(2023, 11, 30), d(2024, 1, 1)),
+            [d(2023, 11, 1), d(2023, 12, 1), d(2024, 1, 1)]
+        );
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let query = parse_query("from=2023-09-01&to=&x");
+        assert_eq!(query, [("from", "2023-09-01"), ("to", ""), ("x", "")]);
+        assert_eq!(
+            parse_date(&query, "from"),
+            Ok(Some(NaiveDate::from_ymd_opt(2023, 9, 1).unwrap()))
+        );
+        assert!(parse_date(&query, "to").is_err());
+        assert_eq!(parse_date(&query, "nothing"), Ok(None));
+    }
+}
@@ -0,0 +1,444 @@
+use std::time::Duration;
+
+use chrono::{Datelike, Local};
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use super::agent::{ApolloAgent, PunchType};
+use super::config::{HooksConfig, TelegramConfig};
+use super::control::{punch_via_daemon, send_command};
+use super::hooks::punch_with_hooks;
+use super::notify::{wants_event, Notification, NotificationKind, Sink};
+use super::plan::{DayPlan, PunchStatus};
+
+/// Seconds `getUpdates` waits for new messages before returning empty.
+const LONG_POLL_SECONDS: u64 = 30;
+
+/// Commands older than this were sent while the bot was down, they are not run.
+const MAX_COMMAND_AGE_SECONDS: i64 = 300;
+
+/// Telegram rejects longer messages.
+const MAX_MESSAGE_CHARS: usize = 4096;
+
+const HELP: &str = "/today - today's schedule
+/calendar - this month's schedule
+/punchin - punch in now
+/punchout - punch out now
+/skip - skip today's pending auto punches
+/plan - today's auto punch plan";
+
+/// Minimal Telegram Bot API client.
+pub struct TelegramApi {
+    client: reqwest::blocking::Client,
+    base_url: String,
+}
+
+impl TelegramApi {
+    pub fn new(config: &TelegramConfig) -> Self {
+        TelegramApi {
+            client: reqwest::blocking::Client::builder()
+                // longer than the long poll
+                .timeout(Duration::from_secs(LONG_POLL_SECONDS + 10))
+                .build()
+                .unwrap(),
+            base_url: format!(
+                "{}/bot{}",
+                config.api_url.trim_end_matches('/'),
+                config.token
+            ),
+        }
+    }
+
+    fn call(&self, method: &str, params: Value) -> Result<Value, String> {
+        // the url holds the token, keep it out of the errors
+        let resp: Value = self
+            .client
+            .post(format!("{}/{}", self.base_url, method))
+            .json(&params)
+            .send()
+            .and_then(|r| r.json())
+            .map_err(|e| format!("telegram {} failed: {}", method, e.without_url()))?;
+
+        if resp["ok"].as_bool() == Some(true) {
+            Ok(resp["result"].clone())
+        } else {
+            Err(format!("telegram {} failed: {}", method, resp))
+        }
+    }
+
+    pub fn get_updates(&self, offset: i64, timeout: u64) -> Result<Vec<Value>, String> {
+        self.call(
+            "getUpdates",
+            json!({ "offset": offset, "timeout": timeout, "allowed_updates": ["message"] }),
+        )
+        .map(|v| v.as_array().cloned().unwrap_or_default())
+    }
+
+    pub fn send_message(&self, chat_id: i64, text: &str) -> Result<(), String> {
+        let text = if text.chars().count() > MAX_MESSAGE_CHARS {
+            let mut truncated: String = text.chars().take(MAX_MESSAGE_CHARS - 1).collect();
+            truncated.push('…');
+            truncated
+        } else {
+            text.to_string()
+        };
+
+        self.call("sendMessage", json!({ "chat_id": chat_id, "text": text }))
+            .map(|_| ())
+    }
+}
+
+/// Sends notifications to every allowed chat.
+pub struct TelegramSink {
+    api: TelegramApi,
+    config: TelegramConfig,
+}
+
+impl TelegramSink {
+    pub fn new(config: TelegramConfig) -> Self {
+        TelegramSink {
+            api: TelegramApi::new(&config),
+            config,
+        }
+    }
+}
+
+impl Sink for TelegramSink {
+    fn name(&self) -> String {
+        "telegram".to_string()
+    }
+
+    fn wants(&self, kind: NotificationKind) -> bool {
+        wants_event(&self.config.events, self.config.daily_summary, kind)
+    }
+
+    fn send(&self, notification: &Notification) -> Result<(), String> {
+        // a blocked chat must not keep the others from being notified
+        let errors: Vec<String> = self
+            .config
+            .allowed_chats
+            .iter()
+            .filter_map(|chat| {
+                self.api
+                    .send_message(*chat, &notification.to_string())
+                    .err()
+                    .map(|e| format!("chat {}: {}", chat, e))
+            })
+            .collect();
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+}
+
+/// Long-polls Telegram and runs the commands of allowed chats with an `ApolloAgent`,
+/// commands are handled one at a time.
+pub struct TelegramBot {
+    api: TelegramApi,
+    agent: ApolloAgent,
+    hooks: HooksConfig,
+    allowed_chats: Vec<i64>,
+    state_filename: String,
+    lock_filename: String,
+    socket_filename: String,
+    /// id of the next update to fetch
+    offset: i64,
+}
+
+impl TelegramBot {
+    pub fn new(
+        agent: ApolloAgent,
+        hooks: &HooksConfig,
+        config: &TelegramConfig,
+        state_filename: &str,
+        lock_filename: &str,
+        socket_filename: &str,
+    ) -> Self {
+        TelegramBot {
+            api: TelegramApi::new(config),
+            agent,
+            hooks: hooks.clone(),
+            allowed_chats: config.allowed_chats.clone(),
+            state_filename: state_filename.to_string(),
+            lock_filename: lock_filename.to_string(),
+            socket_filename: socket_filename.to_string(),
+            offset: 0,
+        }
+    }
+
+    pub fn run(&mut self) -> Result<(), String> {
+        info!(
+            "telegram bot polling, allowed chats: {:?}",
+            self.allowed_chats
+        );
+
+        loop {
+            if let Err(e) = self.poll_once(LONG_POLL_SECONDS) {
+                warn!("{}", e);
+                std::thread::sleep(Duration::from_secs(5));
+            }
+        }
+    }
+
+    fn poll_once(&mut self, timeout: u64) -> Result<(), String> {
+        for update in self.api.get_updates(self.offset, timeout)? {
+            if let Some(id) = update["update_id"].as_i64() {
+                self.offset = self.offset.max(id + 1);
+            }
+
+            let message = &update["message"];
+            let (chat, text) = match (message["chat"]["id"].as_i64(), message["text"].as_str()) {
+                (Some(chat), Some(text)) => (chat, text),
+                _ => continue,
+            };
+
+            if !self.allowed_chats.contains(&chat) {
+                warn!("ignored message from unauthorized chat {}", chat);
+                continue;
+            }
+
+            let age = Local::now().timestamp() - message["date"].as_i64().unwrap_or(0);
+            let reply = if age > MAX_COMMAND_AGE_SECONDS {
+                format!("ignored {}, it was sent {}s ago", text, age)
+            } else {
+                info!("chat {}: {}", chat, text);
+                self.handle(text)
+            };
+
+            self.api.send_message(chat, &reply)?;
+        }
+
+        Ok(())
+    }
+
+    fn handle(&mut self, text: &str) -> String {
+        // commands in groups are addressed like /plan@some_bot
+        let command = text
+            .split_whitespace()
+            .next()
+            .unwrap_or("")
+            .split('@')
+            .next()
+            .unwrap();
+
+        match command {
+            "/today" => self
+                .with_relogin(|agent| agent.get_today_schedule())
+                .map_or_else(|e| e, |v| v.to_string()),
+            "/calendar" => self.calendar(),
+            "/punchin" => self.punch(PunchType::PunchIn),
+            "/punchout" => self.punch(PunchType::PunchOut),
+            "/skip" => send_command(&self.socket_filename, "skip today").unwrap_or_else(|e| e),
+            "/plan" => self.plan(),
+            "/start" | "/help" => HELP.to_string(),
+            _ => format!("unknown command {}\n\n{}", command, HELP),
+        }
+    }
+
+    /// Runs `f`, logs in again and retries once if it fails, the session may have expired.
+    fn with_relogin<T, F>(&mut self, f: F) -> Result<T, String>
+    where
+        F: Fn(&ApolloAgent) -> Result<T, String>,
+    {
+        f(&self.agent).or_else(|_| {
+            self.agent.login()?;
+            f(&self.agent)
+        })
+    }
+
+    fn calendar(&mut self) -> String {
+        let today = Local::now().date_naive();
+        match self.with_relogin(|agent| {
+            agent.get_workday_schedules(Some(today.year()), Some(today.month()))
+        }) {
+            Ok(schedules) => schedules
+                .iter()
+                .map(|s| {
+                    format!(
+                        "{}{}",
+                        s,
+                        if s.get_naive_date() == today {
+                            " <-- today"
+                        } else {
+                            ""
+                        }
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join("\n"),
+            Err(e) => e,
+        }
+    }
+
+    fn punch(&mut self, punch_type: PunchType) -> String {
+        // a running daemon would punch its pending punch again later
+        let status = match punch_via_daemon(&self.lock_filename, &self.socket_filename, punch_type)
+        {
+            Ok(Some(v)) => v,
+            Ok(None) => {
+                // the session may have expired, log in before the single attempt
+                if let Err(e) = self.agent.login() {
+                    return format!("{} failed\n{}", punch_type, e);
+                }
+                // never retry a punch, a failed response may still have been recorded
+                punch_with_hooks(&self.agent, &self.hooks, punch_type, None)
+            }
+            Err(e) => return format!("{} failed\n{}", punch_type, e),
+        };
+
+        match status {
+            PunchStatus::Done { response, .. } => format!(
+                "{} done\n{}",
+                punch_type,
+                serde_json::to_string_pretty(&response).unwrap()
+            ),
+            PunchStatus::Failed { error, .. } => format!("{} failed\n{}", punch_type, error),
+            PunchStatus::Skipped { reason, .. } => format!("{} skipped\n{}", punch_type, reason),
+            PunchStatus::Pending => unreachable!(),
+        }
+    }
+
+    fn plan(&self) -> String {
+        let today = Local::now().date_naive();
+
+        match DayPlan::load(&self.state_filename) {
+            Ok(Some(plan)) if plan.date == today => plan.to_string(),
+            Ok(_) => format!("no auto punch plan for {}", today),
+            Err(e) => e,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn config(api_url: String) -> TelegramConfig {
+        TelegramConfig {
+            token: "123:abc".to_string(),
+            api_url,
+            allowed_chats: vec![42],
+            events: vec![NotificationKind::PunchFailed],
+            daily_summary: false,
+        }
+    }
+
+    /// Bot API stand-in answering `getUpdates` with `updates` once, then recording
+    /// `sendMessage` calls till `messages` of them are received. Chat 666 blocked the bot.
+    fn spawn_api_stand_in(
+        updates: Value,
+        messages: usize,
+    ) -> (String, thread::JoinHandle<Vec<(String, Value)>>) {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+
+        let handle = thread::spawn(move || {
+            let mut received = vec![];
+            while received.iter().filter(|(m, _)| m == "sendMessage").count() < messages {
+                let mut request = server.recv().unwrap();
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body).unwrap();
+                let method = request.url().rsplit('/').next().unwrap().to_string();
+                assert!(request.url().starts_with("/bot123:abc/"));
+
+                let body: Value = serde_json::from_str(&body).unwrap();
+                let resp = if method == "getUpdates" {
+                    json!({ "ok": true, "result": updates })
+                } else if body["chat_id"] == 666 {
+                    json!({ "ok": false, "description": "Forbidden: bot was blocked by the user" })
+                } else {
+                    json!({ "ok": true, "result": true })
+                };
+                received.push((method, body));
+                request
+                    .respond(tiny_http::Response::from_string(resp.to_string()))
+                    .unwrap();
+            }
+            received
+        });
+
+        (url, handle)
+    }
+
+    fn update(id: i64, chat: i64, text: &str, date: i64) -> Value {
+        json!({
+            "update_id": id,
+            "message": { "chat": { "id": chat }, "text": text, "date": date }
+        })
+    }
+
+    #[test]
+    fn test_poll_once() {
+        let now = Local::now().timestamp();
+        let (url, api) = spawn_api_stand_in(
+            json!([
+                update(7, 42, "/plan@apollo_bot", now),
+                // never answered, a punch would need Mayo
+                update(8, 666, "/punchin", now),
+                update(9, 42, "/punchout", now - 3600),
+                update(10, 42, "/hello", now),
+            ]),
+            3,
+        );
+
+        let mut bot = TelegramBot::new(
+            ApolloAgent::new("user", "password", "company"),
+            &HooksConfig::default(),
+            &config(url),
+            "/nonexistent/apollo.state.json",
+            "/nonexistent/apollo.lock",
+            "/nonexistent/apollo.sock",
+        );
+        bot.poll_once(0).unwrap();
+        assert_eq!(bot.offset, 11);
+
+        let received = api.join().unwrap();
+        assert_eq!(received[0].0, "getUpdates");
+        let replies: Vec<&Value> = received[1..].iter().map(|(_, body)| body).collect();
+        assert!(replies.iter().all(|r| r["chat_id"] == 42));
+        assert!(replies[0]["text"]
+            .as_str()
+            .unwrap()
+            .starts_with("no auto punch plan for"));
+        assert!(replies[1]["text"]
+            .as_str()
+            .unwrap()
+            .starts_with("ignored /punchout, it was sent"));
+        assert!(replies[2]["text"]
+            .as_str()
+            .unwrap()
+            .starts_with("unknown command /hello"));
+    }
+
+    #[test]
+    fn test_sink() {
+        let (url, api) = spawn_api_stand_in(json!([]), 2);
+        let mut config = config(url);
+        config.allowed_chats = vec![666, 42];
+        let sink = TelegramSink::new(config);
+
+        assert!(sink.wants(NotificationKind::PunchFailed));
+        assert!(!sink.wants(NotificationKind::DayPlanned));
+        // the blocked chat fails, but the next one is still notified
+        let e = sink
+            .send(&Notification::LoginFailed {
+                at: Local::now(),
+                error: "[400][Failed] wrong password".to_string(),
+            })
+            .unwrap_err();
+        assert!(e.starts_with("chat 666: "));
+        assert!(e.contains("blocked"));
+
+        let received = api.join().unwrap();
+        assert_eq!(received[1].1["chat_id"], 42);
+        assert!(received[1].1["text"]
+            .as_str()
+            .unwrap()
+            .ends_with("wrong password"));
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::sync::Mutex;
+
+use reqwest::blocking::{Client, Request};
+use serde::{Deserialize, Serialize};
+
+use super::redact::Redactor;
+use super::utils::HttpResponse;
+
+/// Executes the requests built by `ApolloAgent`.
+pub trait Transport: Send + Sync {
+    fn execute(&self, request: Request) -> Result<HttpResponse, String>;
+}
+
+/// Sends requests to Mayo for real.
+pub struct LiveTransport {
+    client: Client,
+}
+
+impl LiveTransport {
+    pub fn new(client: Client) -> Self {
+        LiveTransport { client }
+    }
+}
+
+impl Transport for LiveTransport {
+    fn execute(&self, request: Request) -> Result<HttpResponse, String> {
+        let resp = self.client.execute(request).map_err(|e| e.to_string())?;
+
+        let status = resp.status().as_u16();
+        let headers = resp
+            .headers()
+            .iter()
+            .map(|(k, v)| {
+                (
+                    k.to_string(),
+                    String::from_utf8_lossy(v.as_bytes()).to_string(),
+                )
+            })
+            .collect();
+        let body = resp.text().map_err(|e| e.to_string())?;
+
+        Ok(HttpResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Interaction {
+    pub request: RecordedRequest,
+    pub response: HttpResponse,
+}
+
+/// Request/response pairs of one agent session, in the order they were made.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Cassette {
+    pub interactions: Vec<Interaction>,
+}
+
+impl Cassette {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let file =
+            File::open(path).map_err(|e| format!("can't open cassette {}\nreason: {}", path, e))?;
+        serde_json::from_reader(file)
+            .map_err(|e| format!("can't parse cassette {}\nreason: {}", path, e))
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        fs::write(path, serde_json::to_string_pretty(self).unwrap())
+            .map_err(|e| format!("can't write cassette {}\nreason: {}", path, e))
+    }
+}
+
+fn recorded_request(request: &Request, redactor: &Redactor) -> RecordedRequest {
+    RecordedRequest {
+        method: request.method().to_string(),
+        url: redactor.url(request.url().as_str()),
+        headers: request
+            .headers()
+            .iter()
+            .map(|(k, v)| {
+                (
+                    k.to_string(),
+                    redactor.header(k.as_str(), &String::from_utf8_lossy(v.as_bytes())),
+                )
+            })
+            .collect(),
+        body: request
+            .body()
+            .and_then(|b| b.as_bytes())
+            .map(|b| redactor.body(&String::from_utf8_lossy(b)))
+            .unwrap_or_default(),
+    }
+}
+
+/// Passes requests on to `inner`, and writes every redacted pair into a cassette file.
+pub struct RecordingTransport {
+    inner: Box<dyn Transport>,
+    path: String,
+    redactor: Redactor,
+    cassette: Mutex<Cassette>,
+}
+
+impl RecordingTransport {
+    pub fn new(inner: Box<dyn Transport>, path: &str, redactor: Redactor) -> Self {
+        RecordingTransport {
+            inner,
+            path: path.to_string(),
+            redactor,
+            cassette: Mutex::new(Cassette::default()),
+        }
+    }
+}
+
+impl Transport for RecordingTransport {
+    fn execute(&self, request: Request) -> Result<HttpResponse, String> {
+        let recorded = recorded_request(&request, &self.redactor);
+        let resp = self.inner.execute(request)?;
+
+        let mut cassette = self.cassette.lock().unwrap();
+        cassette.interactions.push(Interaction {
+            request: recorded,
+            response: HttpResponse {
+                status: resp.status,
+                headers: resp
+                    .headers
+                    .iter()
+                    .map(|(k, v)| (k.clone(), self.redactor.header(k, v)))
+                    .collect(),
+                body: self.redactor.body(&resp.body),
+            },
+        });
+        // saved after every request, so a crashing session is still captured
+        cassette.save(&self.path)?;
+
+        Ok(resp)
+    }
+}
+
+/// Serves the responses of a cassette back, requests must come in the recorded order.
+pub struct ReplayTransport {
+    interactions: Mutex<VecDeque<Interaction>>,
+    redactor: Redactor,
+}
+
+impl ReplayTransport {
+    pub fn new(cassette: Cassette) -> Self {
+        ReplayTransport {
+            interactions: Mutex::new(cassette.interactions.into()),
+            redactor: Redactor::new(Vec::new()),
+        }
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn execute(&self, request: Request) -> Result<HttpResponse, String> {
+        let method = request.method().to_string();
+        // recorded urls are redacted, so compare redacted
+        let url = self.redactor.url(request.url().as_str());
+
+        let interaction = self
+            .interactions
+            .lock()
+            .unwrap()
+            .pop_front()
+            .ok_or_else(|| format!("cassette exhausted, unexpected {} {}", method, url))?;
+
+        if interaction.request.method != method || interaction.request.url != url {
+            return Err(format!(
+                "cassette mismatch, expect {} {} but got {} {}",
+                interaction.request.method, interaction.request.url, method, url
+            ));
+        }
+
+        Ok(interaction.response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct StubTransport;
+
+    impl Transport for StubTransport {
+        fn execute(&self, request: Request) -> Result<HttpResponse, String> {
+            Ok(HttpResponse {
+                status: 200,
+                headers: vec![("set-cookie".to_string(), "session=abc".to_string())],
+                body: format!(
+                    r#"{{"code":"secret-code","url":"{}"}}"#,
+                    request.url().path()
+                ),
+            })
+        }
+    }
+
+    #[test]
+    fn test_record_then_replay() {
+        let path = std::env::temp_dir()
+            .join(format!("apollo-cassette-test-{}.json", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let client = Client::new();
+        let request = || {
+            client
+                .post("https://asiaauth.mayohr.com/Token")
+                .form(&[("password", "hunter2"), ("companyCode", "ACME")])
+                .build()
+                .unwrap()
+        };
+
+        let recorder = RecordingTransport::new(
+            Box::new(StubTransport),
+            &path,
+            Redactor::new(vec!["hunter2".to_string()]),
+        );
+        assert_eq!(
+            recorder.execute(request()).unwrap().body,
+            r#"{"code":"secret-code","url":"/Token"}"#
+        );
+
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(!content.contains("hunter2"));
+        assert!(!content.contains("secret-code"));
+        assert!(!content.contains("session=abc"));
+
+        let replay = ReplayTransport::new(Cassette::load(&path).unwrap());
+        let resp = replay.execute(request()).unwrap();
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.body, r#"{"code":"<redacted>","url":"/Token"}"#);
+        assert!(replay.execute(request()).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_replay_mismatch() {
+        let replay = ReplayTransport::new(Cassette {
+            interactions: vec![Interaction {
+                request: RecordedRequest {
+                    method: "GET".to_string(),
+                    url: "https://linkup-be.mayohr.com/api/Authorization/GetAuthorized".to_string(),
+                    headers: Vec::new(),
+                    body: String::new(),
+                },
+                response: HttpResponse {
+                    status: 200,
+                    headers: Vec::new(),
+                    body: "{}".to_string(),
+                },
+            }],
+        });
+
+        let request = Client::new()
+            .get("https://pt-be.mayohr.com/api/EmployeeCalendars/scheduling")
+            .build()
+            .unwrap();
+        assert!(replay
+            .execute(request)
+            .unwrap_err()
+            .starts_with("cassette mismatch"));
+    }
+}
@@ -1,14 +1,29 @@
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
 use std::thread::sleep;
+use std::time::Instant;
 
-use chrono::{DateTime, Local};
-use reqwest::blocking::Response;
+use chrono::{DateTime, Duration, Local};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use tracing::{debug, info, warn};
 
-pub fn to_resp_json(resp: Response) -> Result<Value, String> {
-    let status = resp.status();
-    let status_code = status.as_u16();
-    let json = resp.json::<Value>().unwrap();
-    let success = status.is_success() && json.get("error").is_none();
+/// A response read into memory, so it can be traced and recorded before being parsed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+pub fn to_resp_json(resp: HttpResponse) -> Result<Value, String> {
+    let status_code = resp.status;
+    let json = serde_json::from_str::<Value>(&resp.body).map_err(|e| {
+        format!(
+            "[{}][Failed] response is not json: {}\n{}",
+            status_code, e, resp.body
+        )
+    })?;
+    let success = (200..300).contains(&status_code) && json.get("error").is_none();
 
     if success {
         Ok(json)
@@ -22,16 +37,102 @@ pub fn to_resp_json(resp: Response) -> Result<Value, String> {
     }
 }
 
-pub fn sleep_until(target: &DateTime<Local>) {
+/// Upper bound of a single nap. `std::thread::sleep` runs on the monotonic clock,
+/// which stops during suspend, so the wall clock target is re-checked after every nap.
+const MAX_NAP: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Wall clock vs monotonic clock difference (in seconds) within one nap that is
+/// reported as suspend/resume or a clock correction.
+const CLOCK_JUMP_TOLERANCE_SECONDS: i64 = 5;
+
+/// Wake up delay (in seconds) after the target that is reported as late.
+const LATE_TOLERANCE_SECONDS: i64 = 5;
+
+/// Sleeps till `target`, but returns early with the first event received from `events`.
+pub fn sleep_until_interruptible<T>(target: &DateTime<Local>, events: &Receiver<T>) -> Option<T> {
+    sleep_until_or(target, |nap| match events.recv_timeout(nap) {
+        Ok(event) => Some(event),
+        Err(RecvTimeoutError::Timeout) => None,
+        Err(RecvTimeoutError::Disconnected) => {
+            sleep(nap);
+            None
+        }
+    })
+}
+
+/// Naps with `nap` until `target` is reached, stops early if `nap` returns an event.
+fn sleep_until_or<T, F>(target: &DateTime<Local>, mut nap: F) -> Option<T>
+where
+    F: FnMut(std::time::Duration) -> Option<T>,
+{
     let now = Local::now();
     let to_target_duration = target.signed_duration_since(now);
 
-    match to_target_duration.to_std() {
-        Ok(d) => {
-            println!("now={}, sleeps {}s till {}", now, d.as_secs_f64(), target);
-            sleep(d)
+    if to_target_duration.to_std().is_err() {
+        debug!("now={}, target time {} already passed", now, target);
+        return None;
+    }
+
+    info!(
+        "now={}, sleeps {}s till {}",
+        now,
+        to_target_duration.num_milliseconds() as f64 / 1000.0,
+        target
+    );
+
+    loop {
+        let wall_before = Local::now();
+        let nap_duration = match target.signed_duration_since(wall_before).to_std() {
+            Ok(d) if !d.is_zero() => d.min(MAX_NAP),
+            _ => break,
+        };
+
+        let mono_before = Instant::now();
+        let event = nap(nap_duration);
+        let wall_after = Local::now();
+
+        if let Some(event) = event {
+            return Some(event);
         }
-        Err(_) => println!("now={}, target time {} already passed", now, target),
+
+        if let Some(jump) = detect_clock_jump(&wall_before, &wall_after, mono_before.elapsed()) {
+            warn!(
+                "now={}, wall clock moved {}s more than the monotonic clock while sleeping (suspend/resume or clock correction), re-checking target {}",
+                wall_after,
+                jump.num_seconds(),
+                target
+            );
+        }
+    }
+
+    let now = Local::now();
+    let late = now.signed_duration_since(*target);
+    if late > Duration::seconds(LATE_TOLERANCE_SECONDS) {
+        warn!(
+            "now={}, woke up {}s late for target time {}",
+            now,
+            late.num_seconds(),
+            target
+        );
+    }
+
+    None
+}
+
+/// Returns how much further the wall clock moved than the monotonic clock,
+/// if the difference is big enough to be a suspend/resume or clock correction.
+fn detect_clock_jump(
+    wall_before: &DateTime<Local>,
+    wall_after: &DateTime<Local>,
+    mono_elapsed: std::time::Duration,
+) -> Option<Duration> {
+    let wall_elapsed = wall_after.signed_duration_since(*wall_before);
+    let drift = wall_elapsed - Duration::from_std(mono_elapsed).unwrap();
+
+    if drift.num_seconds().abs() >= CLOCK_JUMP_TOLERANCE_SECONDS {
+        Some(drift)
+    } else {
+        None
     }
 }
 
@@ -44,9 +145,45 @@ mod tests {
     #[test]
     #[ignore = "manual run only"]
     fn test_sleep_until() {
+        let (_tx, rx) = std::sync::mpsc::channel::<()>();
         let now = Local::now();
-        sleep_until(&now.checked_add_signed(Duration::seconds(1)).unwrap());
+        sleep_until_interruptible(&now.checked_add_signed(Duration::seconds(1)).unwrap(), &rx);
         let after = Local::now();
         assert!(after.signed_duration_since(now).num_seconds() >= 1)
     }
+
+    #[test]
+    fn test_sleep_until_interruptible() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        tx.send("wake up").unwrap();
+
+        let now = Local::now();
+        let target = now.checked_add_signed(Duration::hours(1)).unwrap();
+        assert_eq!(sleep_until_interruptible(&target, &rx), Some("wake up"));
+        assert!(Local::now().signed_duration_since(now).num_seconds() < 1);
+    }
+
+    #[test]
+    fn test_detect_clock_jump() {
+        let before = Local::now();
+        let nap = std::time::Duration::from_secs(30);
+
+        // normal nap
+        let after = before + Duration::seconds(30);
+        assert!(detect_clock_jump(&before, &after, nap).is_none());
+
+        // suspended for an hour in the middle of the nap
+        let after = before + Duration::seconds(3630);
+        assert_eq!(
+            detect_clock_jump(&before, &after, nap),
+            Some(Duration::seconds(3600))
+        );
+
+        // clock corrected backwards
+        let after = before - Duration::seconds(30);
+        assert_eq!(
+            detect_clock_jump(&before, &after, nap),
+            Some(Duration::seconds(-60))
+        );
+    }
 }
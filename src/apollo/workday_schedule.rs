@@ -1,9 +1,10 @@
 use super::agent::PunchType;
-use chrono::{DateTime, Duration, Local};
+use chrono::{DateTime, Duration, Local, NaiveDate};
 use rand::{thread_rng, Rng};
-use serde_json::Value;
+use serde_json::{json, Value};
 use std::fmt::Display;
 
+#[derive(Clone, Debug, PartialEq)]
 pub struct WorkdaySchedule {
     date: String,
     work_on_time: Option<DateTime<Local>>,
@@ -27,11 +28,11 @@ impl Display for WorkdaySchedule {
 }
 
 fn parse_as_local_time(v: &Value) -> Option<DateTime<Local>> {
-    return v.as_str().and_then(|v| {
+    v.as_str().and_then(|v| {
         DateTime::parse_from_rfc3339(v)
             .map(|v| Some(v.with_timezone(&Local)))
             .unwrap()
-    });
+    })
 }
 
 impl WorkdaySchedule {
@@ -60,6 +61,17 @@ impl WorkdaySchedule {
         }
     }
 
+    pub fn to_json(&self) -> Value {
+        json!({
+            "date": self.date,
+            "work_day": self.is_work_day(),
+            "description": self.description(),
+            "work_on_time": self.work_on_time,
+            "work_off_time": self.work_off_time,
+            "memo": self.memo,
+        })
+    }
+
     pub fn is_work_day(&self) -> bool {
         self.work_on_time.is_some() || self.work_off_time.is_some()
     }
@@ -82,6 +94,17 @@ impl WorkdaySchedule {
         self.date.as_str()
     }
 
+    pub fn get_naive_date(&self) -> NaiveDate {
+        NaiveDate::parse_from_str(&self.date, "%Y-%m-%d").unwrap()
+    }
+
+    pub fn get_shift_time(&self, punch_type: PunchType) -> Option<DateTime<Local>> {
+        match punch_type {
+            PunchType::PunchIn => self.work_on_time,
+            PunchType::PunchOut => self.work_off_time,
+        }
+    }
+
     pub fn get_punch_time_with_jitter(
         &self,
         punch_type: PunchType,
@@ -89,6 +112,9 @@ impl WorkdaySchedule {
     ) -> DateTime<Local> {
         let mut rng = thread_rng();
         let jitter_second = jitter.unwrap_or(60) as i64;
+        if jitter_second == 0 {
+            return self.get_shift_time(punch_type).unwrap();
+        }
 
         match punch_type {
             PunchType::PunchIn => self
@@ -113,7 +139,6 @@ impl WorkdaySchedule {
 mod tests {
     use super::*;
     use chrono::TimeZone;
-    use serde_json::json;
 
     #[test]
     fn test_description() {
@@ -130,7 +155,7 @@ mod tests {
             (
                 WorkdaySchedule {
                     date: "2023-01-03".to_string(),
-                    work_on_time: Some(Local.with_ymd_and_hms(2023, 1, 3, 09, 0, 0).unwrap()),
+                    work_on_time: Some(Local.with_ymd_and_hms(2023, 1, 3, 9, 0, 0).unwrap()),
                     work_off_time: Some(Local.with_ymd_and_hms(2023, 1, 3, 18, 0, 0).unwrap()),
                     memo: None,
                 },
@@ -139,7 +164,7 @@ mod tests {
             (
                 WorkdaySchedule {
                     date: "2023-01-03".to_string(),
-                    work_on_time: Some(Local.with_ymd_and_hms(2023, 1, 3, 09, 0, 0).unwrap()),
+                    work_on_time: Some(Local.with_ymd_and_hms(2023, 1, 3, 9, 0, 0).unwrap()),
                     work_off_time: Some(Local.with_ymd_and_hms(2023, 1, 3, 18, 0, 0).unwrap()),
                     memo: Some("補班日".to_string()),
                 },
@@ -211,6 +236,28 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_to_json() {
+        let schedule = WorkdaySchedule {
+            date: "2023-01-03".to_string(),
+            work_on_time: Some(Local.with_ymd_and_hms(2023, 1, 3, 9, 0, 0).unwrap()),
+            work_off_time: None,
+            memo: Some("補班日".to_string()),
+        };
+
+        assert_eq!(
+            schedule.to_json(),
+            json!({
+                "date": "2023-01-03",
+                "work_day": true,
+                "description": "工作日(補班日)",
+                "work_on_time": "2023-01-03T09:00:00+08:00",
+                "work_off_time": null,
+                "memo": "補班日",
+            })
+        );
+    }
+
     #[test]
     fn test_from_json_holiday() {
         let json = json!({
@@ -1,15 +1,36 @@
 mod apollo;
 
-use std::fs::File;
-use std::io::Write;
-use std::process;
+use std::env;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::ExitCode;
+use std::thread;
 
 use crate::apollo::agent::{ApolloAgent, PunchType};
-use apollo::utils::sleep_until;
-use chrono::{Local, TimeZone};
-use clap::{Parser, Subcommand};
-use serde::{Deserialize, Serialize};
-use serde_json::{json, to_string_pretty};
+use crate::apollo::config::{
+    check_config_file, format_config, get_config_dir, get_config_filename, get_lock_filename,
+    get_socket_filename, get_state_filename, list_profiles, load_accounts_file, load_config_file,
+    load_config_value, load_logging_config, resolve_config_name, update_passwords,
+    write_config_file, ConfigPayload, ConfigSource, PasswordSource,
+};
+use crate::apollo::control::send_command;
+use crate::apollo::daemon::{do_punch, Daemon};
+use crate::apollo::lock::InstanceLock;
+use crate::apollo::logging::{self, LogFormat, LogOptions, LogRotation};
+use crate::apollo::metrics;
+use crate::apollo::redact::redact_json;
+use crate::apollo::secret::{
+    decrypt_password, encrypt_password, set_passphrase_fd, store_password, with_passphrase,
+    PasswordStore,
+};
+use crate::apollo::server::ApiServer;
+use crate::apollo::telegram::TelegramBot;
+use chrono::Local;
+use clap::{ArgAction, Parser, Subcommand};
+use tracing::{error, info_span};
+
+/// Exit status of fatal errors.
+const FATAL_EXIT_CODE: u8 = 255;
 
 #[derive(Parser, Debug)]
 #[command(name = "apollo")]
@@ -18,28 +39,130 @@ struct Cli {
     #[arg(
         short,
         long,
-        default_value = "config.json",
-        help = "Config filename, you could skip the .json extension"
+        conflicts_with = "profile",
+        help = "Config filename, JSON, TOML or YAML by its extension, which you could skip. Without it, --profile or the APOLLO_CONFIG env var, the config of the working directory or of the config directory is used"
     )]
-    config: String,
+    config: Option<String>,
+    #[arg(
+        long,
+        help = "Use the config <PROFILE> (.json, .toml or .yaml) in the config directory, $XDG_CONFIG_HOME/apollo or ~/.config/apollo"
+    )]
+    profile: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        help = "Login and plan as usual, but only print the punch requests instead of sending them"
+    )]
+    dry_run: bool,
+    #[arg(
+        long,
+        global = true,
+        help = "Log every request and response made to Mayo, with credentials redacted"
+    )]
+    trace_http: bool,
+    #[arg(
+        long,
+        global = true,
+        value_name = "CASSETTE",
+        conflicts_with = "replay_http",
+        help = "Record every request and response, redacted, into this cassette file"
+    )]
+    record_http: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        value_name = "CASSETTE",
+        help = "Answer requests from this cassette file instead of calling Mayo"
+    )]
+    replay_http: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        value_name = "FD",
+        help = "Read the passphrase of encrypted passwords from this file descriptor, otherwise APOLLO_PASSPHRASE or a terminal prompt gives it"
+    )]
+    passphrase_fd: Option<i32>,
+    #[arg(
+        short,
+        long,
+        global = true,
+        action = ArgAction::Count,
+        help = "More verbose logging, -v for debug, -vv for trace"
+    )]
+    verbose: u8,
+    #[arg(
+        short,
+        long,
+        global = true,
+        action = ArgAction::Count,
+        help = "Less verbose logging, -q for warnings, -qq for errors only"
+    )]
+    quiet: u8,
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        help = "Log format, defaults to logging.format of the config, or text"
+    )]
+    log_format: Option<LogFormat>,
+    #[arg(
+        long,
+        global = true,
+        help = "Also write logs into this file, rotated by --log-rotation, defaults to logging.file of the config"
+    )]
+    log_file: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        help = "Defaults to logging.rotation of the config, or daily"
+    )]
+    log_rotation: Option<LogRotation>,
+    #[arg(
+        long,
+        global = true,
+        help = "Rotated log files to keep, defaults to logging.max_files of the config, or 30"
+    )]
+    log_max_files: Option<usize>,
     #[command(subcommand)]
     command: SubCommands,
 }
 
 #[derive(Debug, Subcommand)]
 enum SubCommands {
-    #[command(about = "Initialize config file, the default config filename will be config.json")]
+    #[command(
+        about = "Initialize config file, at the path `config path` prints. Asks for the credentials and checks them with a trial login"
+    )]
     Init {
-        #[arg()]
-        username: String,
-        #[arg()]
-        password: String,
-        #[arg()]
-        company: String,
+        #[arg(long, help = "Company code, asked when not given")]
+        company: Option<String>,
+        #[arg(long, help = "Employee number, asked when not given")]
+        username: Option<String>,
+        #[arg(long, help = "Overwrite an existing config")]
+        force: bool,
+        #[arg(
+            long,
+            value_enum,
+            default_value = "plain",
+            help = "Where to keep the password, the config only refers to it unless plain. The env and command password sources are set by editing the config"
+        )]
+        store: PasswordStore,
     },
 
     #[command(about = "Auto punch by workday calendar setting")]
-    AutoPunch {},
+    AutoPunch {
+        #[arg(
+            long,
+            conflicts_with = "all",
+            help = "Serve Prometheus metrics on this address, e.g. 127.0.0.1:9898, with a single account only"
+        )]
+        metrics_listen: Option<String>,
+        #[arg(
+            long,
+            help = "Punch every account of a multi-account config, each with its own state, lock and control socket named like <config>.<account>"
+        )]
+        all: bool,
+    },
 
     #[command(about = "Punch in")]
     PunchIn {},
@@ -49,60 +172,213 @@ enum SubCommands {
 
     #[command(about = "display worday calendar")]
     Calendar {},
+
+    #[command(about = "Show whether an auto punch daemon is running with this config")]
+    Status {},
+
+    #[command(about = "Serve agent operations as an HTTP JSON API")]
+    Serve {
+        #[arg(long, default_value = "127.0.0.1:8080", help = "Address to listen on")]
+        listen: String,
+        #[arg(
+            long,
+            env = "APOLLO_API_TOKEN",
+            hide_env_values = true,
+            help = "Bearer token clients must send in the Authorization header"
+        )]
+        token: String,
+    },
+
+    #[command(about = "Take commands from the Telegram chats in the telegram config")]
+    Bot {},
+
+    #[command(about = "Control the running auto punch daemon")]
+    Ctl {
+        #[command(subcommand)]
+        command: CtlCommands,
+    },
+
+    #[command(about = "Locate and inspect config files")]
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct ConfigPayload {
-    username: String,
-    password: String,
-    company: String,
+#[derive(Debug, Subcommand)]
+enum ConfigCommands {
+    #[command(about = "List the profiles in the config directory")]
+    List {},
+
+    #[command(about = "Print the config in use, with secrets redacted")]
+    Show {},
+
+    #[command(about = "Print the filename of the config in use")]
+    Path {},
+
+    #[command(about = "Encrypt the plain passwords of the config with a passphrase")]
+    Encrypt {},
+
+    #[command(about = "Turn the encrypted passwords of the config back into plain text")]
+    Decrypt {},
+
+    #[command(about = "Check the config for unknown keys and invalid values")]
+    Validate {},
+}
+
+#[derive(Debug, Subcommand)]
+enum CtlCommands {
+    #[command(about = "Show today's plan and the next action")]
+    Status {},
+
+    #[command(about = "Skip the pending punches of today")]
+    Skip {
+        #[arg(value_parser = ["today"])]
+        day: String,
+    },
+
+    #[command(about = "Postpone a pending punch, e.g. delay punch-out 90m")]
+    Delay {
+        #[arg(value_parser = ["punch-in", "punch-out"])]
+        punch: String,
+        #[arg(help = "Duration like 90m, 1h30m or 45s")]
+        duration: String,
+    },
+
+    #[command(about = "Punch right now")]
+    PunchNow {
+        #[arg(value_parser = ["in", "out"])]
+        punch: String,
+    },
+
+    #[command(about = "Re-fetch the schedule and re-arrange the pending punches of today")]
+    Replan {},
 }
 
-fn get_config_filename(config_name: &String) -> String {
-    if config_name.ends_with(".json") {
-        config_name.clone()
-    } else {
-        format!("{}.json", config_name)
+impl CtlCommands {
+    /// The line sent over the control socket.
+    fn to_command_line(&self) -> String {
+        match self {
+            CtlCommands::Status {} => "status".to_string(),
+            CtlCommands::Skip { day } => format!("skip {}", day),
+            CtlCommands::Delay { punch, duration } => format!("delay {} {}", punch, duration),
+            CtlCommands::PunchNow { punch } => format!("punch-now {}", punch),
+            CtlCommands::Replan {} => "replan".to_string(),
+        }
     }
 }
 
-fn write_config_file(config_name: &String, username: &String, password: &String, company: &String) {
-    let json = json!({
-        "username": username,
-        "password": password,
-        "company": company,
-    });
-    let config_filename = get_config_filename(config_name);
+fn prepare_agent(config: &ConfigPayload, args: &Cli) -> Result<ApolloAgent, String> {
+    let password = config.resolve_password()?;
+    let mut agent = ApolloAgent::new(
+        config.username.as_str(),
+        password.as_str(),
+        config.company.as_str(),
+    );
+    agent.set_mayo(&config.mayo);
+    agent.set_dry_run(args.dry_run);
+    agent.set_trace_http(args.trace_http);
+    if let Some(path) = &args.record_http {
+        agent.record_http(path);
+    }
+    if let Some(path) = &args.replay_http {
+        agent.replay_http(path)?;
+    }
+    // the daemon logs in by itself, so login failures get notified
+    if !matches!(args.command, SubCommands::AutoPunch { .. }) {
+        agent.login()?;
+    }
 
-    let mut file = File::create(config_filename).unwrap();
-    file.write_all(to_string_pretty(&json).unwrap().as_bytes())
-        .unwrap();
+    Ok(agent)
 }
 
-fn prepare_agent(config_name: &String) -> Result<ApolloAgent, String> {
-    let config_filename = get_config_filename(config_name);
-    let file = File::open(&config_filename).map_err(|e| {
-        format!(
-            r#"can't open {}
-reason: {}
-
-if this is your first time usage, try call init subcommand first,
-        "#,
-            &config_filename, e
-        )
-    })?;
-    let config: ConfigPayload = serde_json::from_reader(file)
-        .map_err(|e| format!("can't parse {} into json.\nreason: {}", &config_filename, e))?;
+/// Asks for a line on the terminal, e.g. "company code: ".
+fn prompt(label: &str) -> Result<String, String> {
+    print!("{}: ", label);
+    io::stdout().flush().map_err(|e| e.to_string())?;
+
+    let mut line = String::new();
+    io::stdin()
+        .read_line(&mut line)
+        .map_err(|e| format!("can't read {}: {}", label, e))?;
+    let line = line.trim().to_string();
+    if line.is_empty() {
+        return Err(format!("{} is required", label));
+    }
+    Ok(line)
+}
 
-    let mut agent = ApolloAgent::new(config.username, config.password, config.company);
-    agent.login()?;
+fn init_config(config_name: &String, args: &Cli) -> Result<(), String> {
+    let (company, username, force, store) = match &args.command {
+        SubCommands::Init {
+            company,
+            username,
+            force,
+            store,
+        } => (company, username, *force, *store),
+        _ => unreachable!(),
+    };
 
-    Ok(agent)
+    let config_filename = get_config_filename(config_name);
+    if Path::new(&config_filename).exists() && !force {
+        return Err(format!(
+            "{} already exists, pass --force to overwrite it",
+            config_filename
+        ));
+    }
+
+    let company = match company {
+        Some(v) => v.clone(),
+        None => prompt("company code")?,
+    };
+    let username = match username {
+        Some(v) => v.clone(),
+        None => prompt("employee number")?,
+    };
+    // never taken from the command line, it would show up in the shell history and ps
+    let password = rpassword::prompt_password("password: ")
+        .map_err(|e| format!("can't read password: {}", e))?;
+
+    let mut config = ConfigPayload {
+        username,
+        password: PasswordSource::Plain(password.clone()),
+        company,
+        catch_up: Default::default(),
+        planning: Default::default(),
+        notifications: Default::default(),
+        telegram: None,
+        mqtt: None,
+        hooks: Default::default(),
+        presence: None,
+        punch: Default::default(),
+        mayo: Default::default(),
+        logging: Default::default(),
+    };
+
+    // prepare_agent logs in
+    let agent = prepare_agent(&config, args)
+        .map_err(|e| format!("trial login failed, nothing written\n{}", e))?;
+    let today = agent
+        .get_today_schedule()
+        .map_err(|e| format!("can't get today's schedule, nothing written\n{}", e))?;
+    println!("logged in, today: {}", today);
+
+    config.password = store_password(
+        store,
+        config_name,
+        &password,
+        &config.username,
+        &config.company,
+    )?;
+    write_config_file(config_name, &config)?;
+    println!("wrote {}", config_filename);
+
+    Ok(())
 }
 
-fn print_calendars(agent: &ApolloAgent) {
+fn print_calendars(agent: &ApolloAgent) -> Result<(), String> {
     let today = Local::now().format("%Y-%m-%d").to_string();
-    let schedules = agent.get_workday_schedules(None, None).unwrap();
+    let schedules = agent.get_workday_schedules(None, None)?;
     for s in schedules {
         println!(
             "{}{}",
@@ -114,107 +390,273 @@ fn print_calendars(agent: &ApolloAgent) {
             }
         );
     }
+    Ok(())
 }
 
-fn _do_auto_punch(agent: &mut ApolloAgent) {
-    // always re-login
-    agent.login().unwrap();
-
-    let schedule = agent.get_today_schedule().unwrap();
+fn print_status(config_name: &String) -> Result<(), String> {
+    match InstanceLock::holder(&get_lock_filename(config_name))? {
+        Some(pid) => println!("auto punch daemon is running, pid {}", pid),
+        None => println!("auto punch daemon is not running"),
+    }
+    Ok(())
+}
 
-    println!("{}", schedule);
+fn print_profiles(config_name: &String) -> Result<(), String> {
+    let config_dir =
+        get_config_dir().ok_or("can't locate the config directory, set XDG_CONFIG_HOME or HOME")?;
+    let profiles = list_profiles(&config_dir)?;
 
-    if !schedule.is_work_day() {
-        println!("{} is not work day", schedule.get_date());
-        return;
+    if profiles.is_empty() {
+        println!("no profiles in {}", config_dir.display());
     }
+    let in_use = get_config_filename(config_name);
+    for (name, filename) in profiles {
+        let marker = if filename == in_use { "*" } else { " " };
+        println!("{} {}\t{}", marker, name, filename);
+    }
+    Ok(())
+}
 
-    let punch_in_time = schedule.get_punch_time_with_jitter(PunchType::PunchIn, None);
-    let punch_out_time = schedule.get_punch_time_with_jitter(PunchType::PunchOut, None);
-
-    println!(
-        r#"Auto punch time arranged:
-    punch in : {}
-    punch out: {}
-    "#,
-        punch_in_time, punch_out_time
-    );
+fn print_config(config_name: &String) -> Result<(), String> {
+    let mut config = load_config_value(config_name)?;
+    redact_json(&mut config);
+    println!("{}", format_config(config_name, &config)?.trim_end());
+    Ok(())
+}
 
-    let mut now = Local::now();
-    if now < punch_in_time {
-        sleep_until(&punch_in_time);
-        _do_punch(agent, PunchType::PunchIn);
-    } else {
-        println!(
-            "punch in skipped, because current time has exceeded the scheduled auto punch time"
-        )
+fn validate_config(config_name: &String) -> Result<(), String> {
+    let config_filename = get_config_filename(config_name);
+    let unknown = check_config_file(config_name)?;
+    if unknown.is_empty() {
+        println!("{} is valid", config_filename);
+        return Ok(());
     }
 
-    now = Local::now();
-    if now < punch_out_time {
-        sleep_until(&punch_out_time);
-        _do_punch(agent, PunchType::PunchOut);
-    } else {
-        println!(
-            "punch out skipped, because current time has exceeded the scheduled auto punch time"
-        )
+    for key in unknown {
+        println!("{}: unknown key", key);
     }
+    Err(format!("{} has unknown keys, typos?", config_filename))
 }
 
-fn _do_punch(agent: &mut ApolloAgent, punch_type: PunchType) {
-    match agent.punch_card(punch_type) {
-        Ok(v) => print!("{}", serde_json::to_string_pretty(&v).unwrap()),
-        Err(e) => println!("{}", e),
+fn encrypt_config(config_name: &String, encrypt: bool) -> Result<(), String> {
+    let changed = with_passphrase(encrypt, |passphrase| {
+        update_passwords(config_name, |password| {
+            if encrypt {
+                encrypt_password(password, passphrase)
+            } else {
+                decrypt_password(password, passphrase)
+            }
+        })
+    })?;
+
+    match changed {
+        0 => println!(
+            "no {} password in {}",
+            if encrypt { "plain" } else { "encrypted" },
+            get_config_filename(config_name)
+        ),
+        n => println!(
+            "{} {} password(s) of {}",
+            if encrypt { "encrypted" } else { "decrypted" },
+            n,
+            get_config_filename(config_name)
+        ),
     }
+    Ok(())
 }
 
-fn auto_punch(agent: &mut ApolloAgent) {
-    loop {
-        _do_auto_punch(agent);
+fn send_control_command(config_name: &String, command: &CtlCommands) -> Result<(), String> {
+    let reply = send_command(
+        &get_socket_filename(config_name),
+        &command.to_command_line(),
+    )?;
+
+    match reply.strip_prefix("error:") {
+        Some(e) => Err(e.trim_start().to_string()),
+        None => {
+            println!("{}", reply);
+            Ok(())
+        }
+    }
+}
 
-        sleep_until(
-            &Local
-                .from_local_datetime(
-                    &Local::now()
-                        .date_naive()
-                        .succ_opt()
-                        .unwrap()
-                        .and_hms_opt(7, 0, 0)
-                        .unwrap(),
-                )
-                .unwrap(),
-        )
+fn start_metrics(metrics_listen: &Option<String>) -> Result<(), String> {
+    if let Some(listen) = metrics_listen {
+        metrics::init();
+        metrics::spawn_metrics_server(listen)?;
     }
+    Ok(())
 }
 
-fn main() {
-    let args = Cli::parse();
+fn run_account(source: ConfigSource, config: ConfigPayload, args: &Cli) -> Result<(), String> {
+    let lock = InstanceLock::acquire(&get_lock_filename(&source.name()))?;
+    let agent = prepare_agent(&config, args)?;
+    Daemon::new(source, config, agent, lock)?.run_retrying();
+    Ok(())
+}
 
-    match args.command {
-        SubCommands::Init {
-            username,
-            password,
-            company,
-        } => write_config_file(&args.config, &username, &password, &company),
+/// Runs one daemon per account on its own thread, an account failing leaves the others running.
+fn auto_punch_all(config_name: &String, args: &Cli) -> Result<(), String> {
+    if args.record_http.is_some() || args.replay_http.is_some() {
+        return Err("--record-http and --replay-http work with a single account only".to_string());
+    }
 
-        _ => {
-            let mut agent = match prepare_agent(&args.config) {
-                Ok(v) => v,
-                Err(e) => {
-                    println!("{}", e);
-                    process::exit(-1);
-                }
+    // no --metrics-listen, the metrics have no account label to tell the accounts apart
+    let accounts = load_accounts_file(config_name)?;
+
+    thread::scope(|scope| {
+        for account in accounts {
+            let source = ConfigSource::Account {
+                config_name: config_name.clone(),
+                account: account.name.clone(),
             };
+            thread::Builder::new()
+                .name(account.name.clone())
+                .spawn_scoped(scope, move || {
+                    // every log line of the account carries its name
+                    let _span = info_span!("account", name = %account.name).entered();
+                    if let Err(e) = run_account(source, account.config, args) {
+                        error!("{}", e);
+                    }
+                })
+                .unwrap();
+        }
+    });
+    Ok(())
+}
+
+fn auto_punch(config_name: &String, args: &Cli) -> Result<(), String> {
+    // only one daemon per config, checked before login
+    let lock = InstanceLock::acquire(&get_lock_filename(config_name))?;
+    if let SubCommands::AutoPunch { metrics_listen, .. } = &args.command {
+        start_metrics(metrics_listen)?;
+    }
+
+    let config = load_config_file(config_name)?;
+    let agent = prepare_agent(&config, args)?;
+    // dropped before the error is returned, which flushes the notifications, e.g. of the
+    // failed login which stopped the daemon
+    Daemon::new(ConfigSource::File(config_name.clone()), config, agent, lock)?.run()
+}
 
-            match args.command {
-                SubCommands::AutoPunch {} => auto_punch(&mut agent),
-                SubCommands::PunchIn {} => _do_punch(&mut agent, PunchType::PunchIn),
-                SubCommands::PunchOut {} => _do_punch(&mut agent, PunchType::PunchOut),
-                SubCommands::Calendar {} => print_calendars(&agent),
-                _ => {
-                    unreachable!("You should not pass!!!")
+fn run(config_name: &String, args: &Cli) -> Result<(), String> {
+    match &args.command {
+        SubCommands::Init { .. } => return init_config(config_name, args),
+        SubCommands::Status {} => return print_status(config_name),
+        SubCommands::Ctl { command } => return send_control_command(config_name, command),
+        SubCommands::Config { command } => {
+            return match command {
+                ConfigCommands::List {} => print_profiles(config_name),
+                ConfigCommands::Show {} => print_config(config_name),
+                ConfigCommands::Path {} => {
+                    println!("{}", get_config_filename(config_name));
+                    Ok(())
                 }
+                ConfigCommands::Encrypt {} => encrypt_config(config_name, true),
+                ConfigCommands::Decrypt {} => encrypt_config(config_name, false),
+                ConfigCommands::Validate {} => validate_config(config_name),
             }
         }
+        SubCommands::AutoPunch { all: true, .. } => return auto_punch_all(config_name, args),
+        SubCommands::AutoPunch { .. } => return auto_punch(config_name, args),
+        _ => {}
+    }
+
+    let config = load_config_file(config_name)?;
+    let agent = prepare_agent(&config, args)?;
+
+    match &args.command {
+        SubCommands::PunchIn {} => do_punch(&agent, &config.hooks, PunchType::PunchIn),
+        SubCommands::PunchOut {} => do_punch(&agent, &config.hooks, PunchType::PunchOut),
+        SubCommands::Calendar {} => print_calendars(&agent)?,
+        SubCommands::Serve { listen, token } => ApiServer::new(
+            agent,
+            &config.hooks,
+            token,
+            &get_state_filename(config_name),
+            &get_lock_filename(config_name),
+            &get_socket_filename(config_name),
+        )?
+        .serve(listen)?,
+        SubCommands::Bot {} => {
+            let telegram = config
+                .telegram
+                .as_ref()
+                .ok_or_else(|| format!("no telegram section in {}", config_name))?;
+            TelegramBot::new(
+                agent,
+                &config.hooks,
+                telegram,
+                &get_state_filename(config_name),
+                &get_lock_filename(config_name),
+                &get_socket_filename(config_name),
+            )
+            .run()?
+        }
+        _ => {
+            unreachable!("You should not pass!!!")
+        }
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args = Cli::parse();
+
+    // before any file is opened, which could take the number of an fd that isn't open
+    if let Some(fd) = args.passphrase_fd {
+        if let Err(e) = set_passphrase_fd(fd) {
+            eprintln!("{}", e);
+            return ExitCode::from(FATAL_EXIT_CODE);
+        }
+    }
+
+    // resolved before logging starts, the config may set the logging defaults
+    let config_name = match resolve_config_name(
+        args.config.as_deref(),
+        args.profile.as_deref(),
+        env::var("APOLLO_CONFIG").ok().as_deref(),
+        Path::new("."),
+        get_config_dir().as_deref(),
+    ) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from(FATAL_EXIT_CODE);
+        }
+    };
+    let logging_config = load_logging_config(&config_name);
+
+    // keep the guard till main returns, so buffered file logs are flushed
+    let _log_guard = match logging::init(&LogOptions {
+        verbosity: args.verbose as i8 - args.quiet as i8,
+        format: args
+            .log_format
+            .or(logging_config.format)
+            .unwrap_or(LogFormat::Text),
+        file: args.log_file.clone().or(logging_config.file),
+        rotation: args
+            .log_rotation
+            .or(logging_config.rotation)
+            .unwrap_or(LogRotation::Daily),
+        max_files: args
+            .log_max_files
+            .or(logging_config.max_files)
+            .unwrap_or(30),
+    }) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::from(FATAL_EXIT_CODE);
+        }
+    };
+
+    // every fatal error ends up here, never process::exit, it would skip the guard's drop
+    match run(&config_name, &args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("{}", e);
+            ExitCode::from(FATAL_EXIT_CODE)
+        }
     }
 }